@@ -0,0 +1,54 @@
+use std::env;
+use std::fs;
+use std::io;
+use std::io::ErrorKind;
+
+// The config file holds `key = value` lines; blank lines and lines starting with '#' are
+// skipped. Only `dbdirectory` is read for now.
+fn default_path() -> Option<String> {
+    if let Ok(v) = env::var("XDG_CONFIG_HOME") {
+        return Some(v+"/hibp/config");
+    }
+    return env::var("HOME").ok().map(|v| v+"/.config/hibp/config");
+}
+
+fn parse(text: &str, pathname: &str) -> io::Result<Vec<(String, String)>> {
+    let mut out: Vec<(String, String)> = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match line.split_once('=') {
+            Some((k, v)) => out.push((String::from(k.trim()), String::from(v.trim()))),
+            None => return Err(io::Error::new(ErrorKind::InvalidData, format!("{}:{}: expected key = value", pathname, i+1))),
+        }
+    }
+    Ok(out)
+}
+
+// The command line and the environment (handled by clap) take precedence over the config
+// file. An explicitly named config file must exist; the default one is optional.
+pub fn dbdirectory(cli: Option<String>, config: Option<&str>) -> io::Result<Option<String>> {
+    if cli.is_some() {
+        return Ok(cli);
+    }
+
+    let pathname = match config {
+        Some(v) => String::from(v),
+        None => match default_path() {
+            Some(v) => v,
+            None => return Ok(None),
+        },
+    };
+    let text = match fs::read_to_string(&pathname) {
+        Ok(v) => v,
+        Err(e) if e.kind() == ErrorKind::NotFound && config.is_none() => return Ok(None),
+        Err(e) => return Err(io::Error::new(e.kind(), format!("{}: {}", pathname, e))),
+    };
+
+    let value = parse(text.as_str(), pathname.as_str())?.into_iter()
+        .find(|(k, _)| k == "dbdirectory")
+        .map(|(_, v)| v);
+    return Ok(value);
+}
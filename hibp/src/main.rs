@@ -1,126 +1,598 @@
+#![allow(clippy::needless_return)]
+
+mod config;
+#[cfg(feature = "build")]
+mod progress;
+#[cfg(feature = "server")]
+mod serve;
+
 use std::io;
-use std::io::{BufReader, prelude::*};
+use std::io::{BufReader, IsTerminal, prelude::*};
 use std::time::Instant;
 
-use clap::Parser;
+use clap::{Args, Parser, Subcommand};
 use hibp_core::db::HIBPDB;
+#[cfg(feature = "build")]
+use hibp_core::compact::IndexLayout;
+use hibp_core::coverage::{Lookup, PrefixRange};
+use hibp_core::decode::{DecodePolicy, PasswordDecoder};
+#[cfg(feature = "build")]
+use hibp_core::gc::GcOptions;
+use hibp_core::residency::{AccessPattern, IndexOptions};
+use hibp_core::rules::RuleSet;
+#[cfg(feature = "download")]
+use hibp_core::throttle::{parse_bytes, ScheduleWindow, UpdateOptions, DEFAULT_CONCURRENCY};
+#[cfg(feature = "build")]
+use progress::{ProgressMode, Renderer};
+use hibp_core::verify::RANGE_COUNT;
 use hibp_core::*;
 
+// Findings are pwned passwords, or a database that failed verification or validation.
+const EXIT_OK: i32 = 0;
+const EXIT_FINDINGS: i32 = 1;
+const EXIT_USAGE: i32 = 2;
+const EXIT_ERROR: i32 = 3;
+
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
-struct Args {
-    #[arg(short, long)]
-    dbdirectory: String,
+struct Cli {
+    #[arg(short, long, global = true, env = "HIBP_DBDIRECTORY",
+        help = "Database directory; falls back to `dbdirectory` in the config file")]
+    dbdirectory: Option<String>,
+
+    #[arg(long, global = true, env = "HIBP_CONFIG",
+        help = "Config file [default: $XDG_CONFIG_HOME/hibp/config or ~/.config/hibp/config]")]
+    config: Option<String>,
+
+    #[cfg(feature = "build")]
+    #[arg(long, global = true,
+        help = "Progress output on stderr: bar, log or quiet [default: bar on a terminal, log otherwise]")]
+    progress: Option<ProgressMode>,
+
+    #[cfg(feature = "build")]
+    #[arg(short, long, global = true, help = "Same as --progress quiet")]
+    quiet: bool,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    #[cfg(feature = "download")]
+    #[command(about = "Download new and changed ranges")]
+    Update {
+        #[arg(long, default_value = "gz")]
+        codec: Codec,
+
+        #[arg(long, default_value_t = DEFAULT_CONCURRENCY, help = "Concurrent downloads")]
+        concurrency: usize,
+
+        #[arg(long, value_parser = parse_bytes, help = "Average download cap in bytes per second, e.g. 512K or 2M")]
+        bandwidth: Option<u64>,
+
+        #[arg(long, help = "Requests started per second")]
+        requests_per_second: Option<f64>,
+
+        #[arg(long, help = "Local time window downloads may run in, e.g. 19:00-07:00; pauses outside it")]
+        window: Option<ScheduleWindow>,
+
+        #[arg(long, default_value_t = PrefixRange::FULL, help = "Only download this prefix interval, e.g. 00000-3FFFF")]
+        prefixes: PrefixRange,
+    },
+
+    #[cfg(feature = "build")]
+    #[command(about = "Build an index from the downloaded ranges")]
+    Construct {
+        #[command(flatten)]
+        layout: LayoutArgs,
+    },
+
+    #[command(about = "Check a password typed at a hidden prompt, or passwords piped one per line; exits 1 if any is pwned")]
+    Check {
+        #[command(flatten)]
+        index: IndexArgs,
+
+        #[arg(long, help = "Read passwords from stdin even when it is a terminal")]
+        stdin: bool,
+    },
+
+    #[command(about = "Count how many lines of a wordlist read from stdin are pwned")]
+    Ingest {
+        #[command(flatten)]
+        index: IndexArgs,
+
+        #[arg(long, default_value = "strict")]
+        decode: DecodePolicy,
+
+        #[arg(long)]
+        hex: bool,
+
+        #[arg(long)]
+        rules: Option<String>,
+    },
+
+    #[cfg(feature = "build")]
+    #[command(about = "Verify index.bin against the ranges; exits 1 on mismatches")]
+    Verify {
+        #[arg(long, default_value_t = 16)]
+        spot_check: usize,
+    },
+
+    #[cfg(feature = "build")]
+    #[command(about = "Validate the range files; exits 1 on bad ranges unless repaired")]
+    Validate {
+        #[arg(long)]
+        repair: bool,
+    },
+
+    #[cfg(feature = "build")]
+    #[command(about = "Remove superseded and temporary range files")]
+    Gc {
+        #[arg(long)]
+        dry_run: bool,
+
+        #[arg(long)]
+        archive: bool,
+    },
+
+    #[cfg(feature = "build")]
+    #[command(about = "Convert every range file to another codec")]
+    Recompress {
+        #[arg(long, default_value = "gz")]
+        codec: Codec,
+    },
+
+    #[cfg(feature = "build")]
+    #[command(about = "Build range.pack from the range directory")]
+    Pack,
+
+    #[cfg(feature = "build")]
+    #[command(about = "Export range.pack into the range directory")]
+    Unpack,
+
+    #[command(about = "Show what the database directory contains")]
+    Status,
+
+    #[cfg(feature = "server")]
+    #[command(about = "Answer hash lookups over HTTP")]
+    Serve {
+        #[command(flatten)]
+        index: IndexArgs,
+
+        #[arg(long, default_value = "127.0.0.1:8080")]
+        listen: String,
+    },
+}
+
+// One layout per build; only the full one can be sharded or get an Eytzinger copy. clap rejects
+// other combinations before any range is read.
+#[derive(Args, Debug)]
+struct LayoutArgs {
+    #[arg(long, conflicts_with_all = ["filter", "learned", "shards", "eytzinger"])]
+    compact: Option<usize>,
+
+    #[arg(long, default_value_t = PrefixRange::FULL, help = "Only index this prefix interval; lookups outside it report not covered")]
+    prefixes: PrefixRange,
+
+    #[arg(long, default_value_t = 1, help = "Split the full index into this many files by prefix")]
+    shards: usize,
+
+    #[arg(long, conflicts_with_all = ["learned", "shards", "eytzinger"])]
+    filter: Option<u32>,
+
+    #[arg(long, conflicts_with = "shards")]
+    learned: bool,
+
+    #[arg(long, conflicts_with = "shards", help = "Also write index.eytzinger; lookups use it whenever it matches index.bin")]
+    eytzinger: bool,
+}
+
+#[derive(Args, Debug)]
+struct IndexArgs {
+    #[arg(long, default_value = "normal")]
+    madvise: AccessPattern,
+
+    #[arg(long)]
+    populate: bool,
+
+    #[arg(long)]
+    preload: bool,
+
+    #[arg(long)]
+    mlock: bool,
+
+    #[arg(long)]
+    huge_pages: bool,
+
+    #[arg(long)]
+    in_memory: bool,
+
+    #[arg(long)]
+    memory_budget_mb: Option<u64>,
+}
+
+impl IndexArgs {
+    fn options(&self) -> IndexOptions {
+        IndexOptions {
+            access: self.madvise,
+            populate: self.populate,
+            preload: self.preload,
+            lock: self.mlock,
+            huge_pages: self.huge_pages,
+            in_memory: self.in_memory,
+            memory_budget: self.memory_budget_mb.map(|v| v << 20),
+        }
+    }
+}
+
+// Prints the verdict for one password. The plaintext is never printed; callers wipe it.
+fn check_one(db: &HIBPDB, hp: &mut HashAndPassword, label: &str) -> bool {
+    if hash_password(hp).is_err() {
+        println!("{}invalid utf-8", label);
+        return false;
+    }
+    match db.lookup(&hp.hash) {
+        Lookup::Found => {}
+        Lookup::NotFound => {
+            println!("{}not pwned", label);
+            return false;
+        }
+        Lookup::NotCovered => {
+            println!("{}not covered by the index", label);
+            return false;
+        }
+    }
+
+    // The index says pwned; the range file, when present, has the count and the final word
+    // for indexes with false positives. Without the codecs there is no range file to read.
+    #[cfg(feature = "build")]
+    let prevalence = db.prevalence(&hp.hash);
+    #[cfg(not(feature = "build"))]
+    let prevalence: io::Result<Option<u64>> = Err(io::Error::from(io::ErrorKind::Unsupported));
+    match prevalence {
+        Ok(Some(count)) => {
+            println!("{}pwned, seen {} times", label, count);
+            return true;
+        }
+        Ok(None) => {
+            println!("{}not pwned", label);
+            return false;
+        }
+        Err(_) => {
+            println!("{}pwned, count unavailable", label);
+            return true;
+        }
+    }
+}
 
-    #[arg(short, long)]
-    update: bool,
+fn check(dbdir: String, index: IndexArgs, stdin_lines: bool) -> io::Result<i32> {
+    let db = HIBPDB::open(dbdir, index.options())?;
 
-    #[arg(short, long)]
-    ingest: bool,
+    let mut hp = HashAndPassword {
+        hash: [0u8; 16],
+        password: vec![],
+    };
+
+    if !stdin_lines && io::stdin().is_terminal() {
+        let mut password = rpassword::prompt_password("Password: ")?.into_bytes();
+        hp.password.extend_from_slice(password.as_slice());
+        password.fill(0);
 
-    #[arg(short, long)]
-    construct: bool,
+        let pwned = check_one(&db, &mut hp, "");
+        hp.password.fill(0);
+        return Ok(if pwned { EXIT_FINDINGS } else { EXIT_OK });
+    }
+
+    let mut pwned = 0u64;
+    let mut linecount = 0u64;
+    let mut stdin = BufReader::new(io::stdin());
+    loop {
+        hp.password.fill(0);
+        hp.password.clear();
+        if stdin.read_until(b'\n', &mut hp.password)? == 0 {
+            break;
+        }
+        while hp.password.last() == Some(&b'\n') || hp.password.last() == Some(&b'\r') {
+            hp.password.pop();
+        }
+        linecount += 1;
+
+        if check_one(&db, &mut hp, format!("{}: ", linecount).as_str()) {
+            pwned += 1;
+        }
+    }
+
+    return Ok(if pwned > 0 { EXIT_FINDINGS } else { EXIT_OK });
 }
 
-fn ingest(args: Args) {
-    let mut db = HIBPDB::new(args.dbdirectory).unwrap();
+const INGEST_BATCH: usize = 1024;
+
+fn ingest(dbdir: String, index: IndexArgs, policy: DecodePolicy, hex: bool, rules: Option<String>) -> io::Result<i32> {
+    let db = HIBPDB::open(dbdir, index.options())?;
 
     let mut stdin = BufReader::new(io::stdin());
-    let mut buff: Vec<u8> = Vec::new();
 
     let mut linecount = 0u64;
     let mut found = 0u64;
     let mut miss = 0u64;
+    let mut not_covered = 0u64;
 
-    let mut hp = HashAndPassword{
+    // Lines are read into the password buffers of a reused batch and hashed together.
+    let mut batch: Vec<HashAndPassword> = (0..INGEST_BATCH).map(|_| HashAndPassword{
         hash: [0u8; 16],
         password: vec![],
+    }).collect();
+    let mut valid = vec![false; INGEST_BATCH];
+
+    let mut decoder = PasswordDecoder::new(policy, hex);
+    let rules = match rules.as_deref() {
+        Some("default") => Some(RuleSet::default()),
+        Some(pathname) => Some(RuleSet::load(pathname)?),
+        None => None,
     };
+    let mut line: Vec<u8> = Vec::new();
 
     let start = Instant::now();
-    loop {
-        buff.clear();
-        let _buff_size = buff.len();
-        let _buff_capacity = buff.capacity();
-        match stdin.read_until('\n' as u8, &mut buff) {
-            Ok(0) => break, // EOF
-            Err(_) => {
+    let mut eof = false;
+    while !eof {
+        let mut n = 0;
+        while n < INGEST_BATCH {
+            line.clear();
+            if stdin.read_until(b'\n', &mut line)? == 0 {
+                eof = true;
                 break;
             }
-            _ => {}
-        }
-        if buff.len() > 0 && buff[buff.len()-1] == b'\n' {
-            buff.pop();
-        }
+            if !line.is_empty() && line[line.len()-1] == b'\n' {
+                line.pop();
+            }
+            linecount += 1;
 
-        hp.password.clear();
-        hp.password.extend(&buff);
-        if hash_password(&mut hp).is_err() {
-            continue;
+            if !decoder.decode(line.as_slice(), &mut batch[n].password) {
+                continue;
+            }
+            match &rules {
+                Some(rules) => {
+                    let password = std::str::from_utf8(batch[n].password.as_slice()).unwrap();
+                    let matches = db.check_variants(rules, password);
+                    for m in matches.iter().filter(|v| v.lookup == Lookup::Found) {
+                        println!("{}\t{}\t{}", linecount, m.rule, m.candidate);
+                    }
+                    // A line counts as a miss only when every variant could be answered.
+                    match (matches.iter().any(|v| v.lookup == Lookup::Found), matches.is_empty()) {
+                        (true, _) => found += 1,
+                        (false, true) => miss += 1,
+                        (false, false) => not_covered += 1,
+                    }
+                }
+                None => n += 1,
+            }
         }
 
-        match db.find(&hp.hash) {
-            Ok(_) => found += 1,
-            Err(_) => miss += 1,
+        hash_password_batch(&mut batch[..n], &mut valid[..n]);
+        for (hp, _) in batch[..n].iter().zip(&valid[..n]).filter(|(_, ok)| **ok) {
+            match db.lookup(&hp.hash) {
+                Lookup::Found => found += 1,
+                Lookup::NotFound => miss += 1,
+                Lookup::NotCovered => not_covered += 1,
+            }
         }
-
-        linecount += 1;
-
     }
 
     let seconds = start.elapsed().as_secs_f64();
     let rate = (linecount as f64 / seconds) as u64;
 
-    let invalid_utf8 = linecount - (found + miss);
-    println!("lines: {}, invalid_utf8: {}, found: {}, miss: {}", linecount, invalid_utf8, found, miss);
-    println!("rate: {}", rate)
+    let stats = decoder.stats;
+    println!("lines: {}, rejected: {}, found: {}, miss: {}", linecount, stats.rejected, found, miss);
+    if !db.coverage.is_full() {
+        println!("not covered: {} (index covers {})", not_covered, db.coverage);
+    }
+    println!("decoded: utf8: {}, hex: {}, latin1: {}, cp1252: {}, lossy: {}", stats.utf8, stats.hex, stats.latin1, stats.cp1252, stats.lossy);
+    println!("rate: {}", rate);
+    return Ok(EXIT_OK);
 }
 
-fn update(args: Args) {
-    let db = HIBPDB::new(args.dbdirectory).unwrap();
-
-    let status: fn(u32) = |range| {
-        println!("{:05X}", range);
-    };
+#[cfg(feature = "download")]
+fn update(dbdir: String, progress: &mut Renderer, codec: Codec, prefixes: PrefixRange, options: UpdateOptions) -> io::Result<i32> {
+    let mut db = HIBPDB::new(dbdir)?;
+    db.codec = codec;
+    db.prefixes = prefixes;
 
-    db.update(status).unwrap();
+    db.update_with(&options, progress)?;
+    return Ok(EXIT_OK);
 }
 
-fn construct(args: Args) {
-    let db = HIBPDB::new(args.dbdirectory).unwrap();
-
-    let status: fn(u32) = |range| {
-        println!("{:05X}", range);
-    };
+#[cfg(feature = "build")]
+fn construct(dbdir: String, progress: &mut Renderer, layout: LayoutArgs) -> io::Result<i32> {
+    let mut db = HIBPDB::new(dbdir)?;
+    db.prefixes = layout.prefixes;
+    db.shard_count = layout.shards;
+    if let Some(suffix_bytes) = layout.compact {
+        db.layout = IndexLayout::Compact { suffix_bytes };
+    } else if let Some(bucket_bits) = layout.filter {
+        db.layout = IndexLayout::Filter { bucket_bits };
+    } else if layout.learned {
+        db.layout = IndexLayout::Learned;
+    }
 
-    db.construct_index(status).unwrap();
+    db.construct_index_with(progress)?;
+    if layout.eytzinger {
+        // Built from the index.bin just written; lookups pick it up when they open the db.
+        db.construct_eytzinger()?;
+    }
+    return Ok(EXIT_OK);
 }
 
-fn main() {
-    let args = Args::parse();
+#[cfg(feature = "build")]
+fn verify(dbdir: String, progress: &mut Renderer, spot_check: usize) -> io::Result<i32> {
+    let db = HIBPDB::new(dbdir)?;
+
+    let report = progress.ranges("verify", RANGE_COUNT as u64, |f| db.verify(spot_check, f))?;
 
-    if args.ingest {
-        ingest(args);
-    } else if args.update {
-        update(args);
-    } else if args.construct {
-        construct(args);
+    for (range, v) in &report.bad_ranges {
+        println!("bad {:05X}: {:?}", range, v);
     }
+    println!("records: {}, spot_checked: {}, bad_ranges: {}", report.records, report.spot_checked.len(), report.bad_ranges.len());
+
+    return Ok(if report.is_ok() { EXIT_OK } else { EXIT_FINDINGS });
 }
 
+#[cfg(feature = "build")]
+fn validate(dbdir: String, progress: &mut Renderer, repair: bool) -> io::Result<i32> {
+    #[cfg(not(feature = "download"))]
+    if repair {
+        return Err(io::Error::new(io::ErrorKind::Unsupported, "repair needs the download feature"));
+    }
+    let db = HIBPDB::new(dbdir)?;
 
+    let report = progress.ranges("validate", RANGE_COUNT as u64, |f| db.validate_ranges(f))?;
 
+    for (range, issues) in &report.bad_ranges {
+        println!("bad {:05X}: {:?}", range, issues);
+    }
+    println!("checked: {}, bad_ranges: {}", report.checked, report.bad_ranges.len());
 
+    #[cfg(feature = "download")]
+    if repair && !report.is_ok() {
+        let ranges: Vec<u32> = report.bad_ranges.keys().copied().collect();
+        progress.ranges("repair", ranges.len() as u64, |f| db.repair(ranges.as_slice(), f))?;
+        return Ok(EXIT_OK);
+    }
+    return Ok(if report.is_ok() { EXIT_OK } else { EXIT_FINDINGS });
+}
 
+#[cfg(feature = "build")]
+fn gc(dbdir: String, dry_run: bool, archive: bool) -> io::Result<i32> {
+    let db = HIBPDB::new(dbdir)?;
 
+    let opts = GcOptions {
+        dry_run,
+        archive,
+    };
+    let report = db.gc(&opts)?;
 
+    let action = if dry_run { "would remove" } else if archive { "archived" } else { "removed" };
+    for filename in &report.superseded {
+        println!("{} {}", action, filename);
+    }
+    for filename in &report.temp {
+        println!("{} {}", if dry_run { "would remove" } else { "removed" }, filename);
+    }
+    println!("kept: {}, superseded: {}, temp: {}", report.kept, report.superseded.len(), report.temp.len());
+    return Ok(EXIT_OK);
+}
 
+#[cfg(feature = "build")]
+fn recompress(dbdir: String, progress: &mut Renderer, codec: Codec) -> io::Result<i32> {
+    let mut db = HIBPDB::new(dbdir)?;
+    db.codec = codec;
 
+    progress.ranges("recompress", RANGE_COUNT as u64, |f| db.recompress(codec, f))?;
+    return Ok(EXIT_OK);
+}
 
+#[cfg(feature = "build")]
+fn pack(dbdir: String, progress: &mut Renderer, pack: bool) -> io::Result<i32> {
+    let mut db = HIBPDB::new(dbdir)?;
 
+    if pack {
+        progress.ranges("pack", RANGE_COUNT as u64, |f| db.pack(f))?;
+    } else {
+        progress.ranges("unpack", RANGE_COUNT as u64, |f| db.unpack(f))?;
+    }
+    return Ok(EXIT_OK);
+}
+
+fn status(dbdir: String) -> io::Result<i32> {
+    let db = HIBPDB::new(dbdir)?;
 
+    let archive = db.archive()?;
+    let present = match archive {
+        Some(archive) => archive.read().unwrap().entries.iter().filter(|v| v.is_present()).count(),
+        None => std::fs::read_dir(db.dbdir.clone()+"/range/").map(|v| v.count()).unwrap_or(0),
+    };
+    println!("dbdirectory: {}", db.dbdir);
+    println!("ranges: {}/{} ({})", present, RANGE_COUNT, if archive.is_some() { "range.pack" } else { "range/" });
+    println!("coverage: {}", db.coverage);
+
+    if let Some(index) = &db.index {
+        println!("index.bin: {} records, {:?} search", index.len(), db.strategy());
+    }
+    if let Some(shards) = &db.shards {
+        for shard in &shards.manifest.shards {
+            println!("{}: {} records ({})", shard.filename, shard.records, shard.prefixes);
+        }
+    }
+    if let Some(eytzinger) = &db.eytzinger {
+        println!("index.eytzinger: {} records", eytzinger.len().saturating_sub(1));
+    }
+    if let Some(compact) = &db.compact {
+        println!("index.compact: {} records, {} byte suffixes, fpr {:.3e}", compact.records, compact.suffix_bytes, compact.false_positive_rate());
+    }
+    if let Some(filter) = &db.filter {
+        println!("index.filter: {} records, fpr {:.3e}", filter.records, filter.false_positive_rate);
+    }
+    if let Some(learned) = &db.learned {
+        println!("index.model: {} records", learned.records);
+    }
+    return Ok(EXIT_OK);
+}
 
+fn main() {
+    let cli = Cli::parse();
 
+    let dbdir = match config::dbdirectory(cli.dbdirectory, cli.config.as_deref()) {
+        Ok(Some(v)) => v,
+        Ok(None) => {
+            eprintln!("no database directory: pass --dbdirectory, set HIBP_DBDIRECTORY or add it to the config file");
+            std::process::exit(EXIT_USAGE);
+        }
+        Err(e) => {
+            eprintln!("config: {}", e);
+            std::process::exit(EXIT_USAGE);
+        }
+    };
 
+    // Only the commands that walk the ranges report progress.
+    #[cfg(feature = "build")]
+    let mode = match cli.quiet {
+        true => ProgressMode::Quiet,
+        false => cli.progress.unwrap_or_else(ProgressMode::detect),
+    };
+    #[cfg(feature = "build")]
+    let progress = &mut Renderer::new(mode);
 
+    let result = match cli.command {
+        #[cfg(feature = "download")]
+        Command::Update { codec, concurrency, bandwidth, requests_per_second, window, prefixes } => {
+            update(dbdir, progress, codec, prefixes, UpdateOptions { concurrency, bandwidth, requests_per_second, window })
+        }
+        #[cfg(feature = "build")]
+        Command::Construct { layout } => construct(dbdir, progress, layout),
+        Command::Check { index, stdin } => check(dbdir, index, stdin),
+        Command::Ingest { index, decode, hex, rules } => ingest(dbdir, index, decode, hex, rules),
+        #[cfg(feature = "build")]
+        Command::Verify { spot_check } => verify(dbdir, progress, spot_check),
+        #[cfg(feature = "build")]
+        Command::Validate { repair } => validate(dbdir, progress, repair),
+        #[cfg(feature = "build")]
+        Command::Gc { dry_run, archive } => gc(dbdir, dry_run, archive),
+        #[cfg(feature = "build")]
+        Command::Recompress { codec } => recompress(dbdir, progress, codec),
+        #[cfg(feature = "build")]
+        Command::Pack => pack(dbdir, progress, true),
+        #[cfg(feature = "build")]
+        Command::Unpack => pack(dbdir, progress, false),
+        Command::Status => status(dbdir),
+        #[cfg(feature = "server")]
+        Command::Serve { index, listen } => serve::serve(dbdir, index.options(), listen.as_str()),
+    };
 
+    match result {
+        Ok(code) => std::process::exit(code),
+        Err(e) => {
+            eprintln!("error: {}", e);
+            std::process::exit(EXIT_ERROR);
+        }
+    }
+}
@@ -0,0 +1,163 @@
+use std::io;
+use std::io::{IsTerminal, Write};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use hibp_core::progress::{Progress, ProgressEvent, ProgressStats};
+
+// `bar` redraws one line on a terminal, `log` prints a line every LOG_INTERVAL, `quiet` prints
+// nothing. All of it goes to stderr so stdout stays parseable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProgressMode {
+    Bar,
+    Log,
+    Quiet,
+}
+
+impl FromStr for ProgressMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "bar" => Ok(ProgressMode::Bar),
+            "log" => Ok(ProgressMode::Log),
+            "quiet" | "none" => Ok(ProgressMode::Quiet),
+            _ => Err(format!("unknown progress mode: {}", s)),
+        }
+    }
+}
+
+impl ProgressMode {
+    pub fn detect() -> Self {
+        if io::stderr().is_terminal() { ProgressMode::Bar } else { ProgressMode::Log }
+    }
+}
+
+const BAR_INTERVAL: Duration = Duration::from_millis(100);
+const LOG_INTERVAL: Duration = Duration::from_secs(10);
+const BAR_WIDTH: usize = 30;
+
+fn duration(v: Duration) -> String {
+    let s = v.as_secs();
+    return format!("{}:{:02}:{:02}", s/3600, s/60%60, s%60);
+}
+
+fn bytes(v: f64) -> String {
+    let units = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut v = v;
+    let mut i = 0;
+    while v >= 1024.0 && i+1 < units.len() {
+        v /= 1024.0;
+        i += 1;
+    }
+    return format!("{:.1} {}", v, units[i]);
+}
+
+pub struct Renderer {
+    pub mode: ProgressMode,
+    pub stats: ProgressStats,
+    drawn: Option<Instant>,
+}
+
+impl Renderer {
+    pub fn new(mode: ProgressMode) -> Self {
+        Self {
+            mode,
+            stats: ProgressStats::default(),
+            drawn: None,
+        }
+    }
+
+    // Drives an operation that only takes an `FnMut(u32)` callback.
+    pub fn ranges<T, F>(&mut self, task: &'static str, total: u64, op: F) -> io::Result<T> where F: FnOnce(&mut (dyn FnMut(u32) + Send)) -> io::Result<T> {
+        self.event(ProgressEvent::Started { task, total });
+        let result = op(&mut |range| self.event(ProgressEvent::Advanced { range, bytes: 0 }));
+        self.event(ProgressEvent::Finished);
+        return result;
+    }
+
+    fn summary(&self) -> String {
+        let s = &self.stats;
+        let (ranges_per_second, bytes_per_second) = s.rate();
+        let mut out = format!("{}: {}/{} ranges ({:.1}%), {:.0} ranges/s", s.task, s.done, s.total, s.fraction()*100.0, ranges_per_second);
+        if s.bytes > 0 {
+            out += format!(", {}/s", bytes(bytes_per_second)).as_str();
+        }
+        if s.errors > 0 {
+            out += format!(", {} errors", s.errors).as_str();
+        }
+        if let Some(eta) = s.eta() {
+            out += format!(", ETA {}", duration(eta)).as_str();
+        }
+        return out;
+    }
+
+    fn bar(&self) -> String {
+        let filled = (self.stats.fraction() * BAR_WIDTH as f64) as usize;
+        return format!("[{}{}] {}", "#".repeat(filled), ".".repeat(BAR_WIDTH - filled), self.summary());
+    }
+
+    fn draw(&mut self, force: bool) {
+        let interval = match self.mode {
+            ProgressMode::Bar => BAR_INTERVAL,
+            ProgressMode::Log => LOG_INTERVAL,
+            ProgressMode::Quiet => return,
+        };
+        if !force && self.drawn.is_some_and(|v| v.elapsed() < interval) {
+            return;
+        }
+        self.drawn = Some(Instant::now());
+
+        let mut stderr = io::stderr().lock();
+        let _ = match self.mode {
+            ProgressMode::Bar => write!(stderr, "\r\x1b[2K{}", self.bar()),
+            _ => writeln!(stderr, "{}", self.summary()),
+        };
+        let _ = stderr.flush();
+    }
+}
+
+impl Progress for Renderer {
+    fn event(&mut self, event: ProgressEvent) {
+        self.stats.record(&event);
+        match event {
+            ProgressEvent::Started { .. } => {
+                self.drawn = None;
+                self.draw(true);
+            }
+            ProgressEvent::Advanced { .. } => self.draw(false),
+            ProgressEvent::Failed { range, error } => {
+                match self.mode {
+                    ProgressMode::Bar => eprint!("\r\x1b[2K{:05X}: {}\n", range, error),
+                    ProgressMode::Log => eprintln!("{:05X}: {}", range, error),
+                    ProgressMode::Quiet => return,
+                }
+                self.draw(true);
+            }
+            ProgressEvent::Paused { resume } => {
+                match self.mode {
+                    ProgressMode::Bar => eprint!("\r\x1b[2K{}: paused outside the schedule window, resuming in {}\n", self.stats.task, duration(resume)),
+                    ProgressMode::Log => eprintln!("{}: paused outside the schedule window, resuming in {}", self.stats.task, duration(resume)),
+                    ProgressMode::Quiet => {}
+                }
+            }
+            ProgressEvent::Finished => {
+                if self.mode == ProgressMode::Quiet {
+                    return;
+                }
+                let s = &self.stats;
+                let mut line = format!("{}: {} ranges in {}", s.task, s.done, duration(s.elapsed()));
+                if s.bytes > 0 {
+                    line += format!(", {}", bytes(s.bytes as f64)).as_str();
+                }
+                if s.errors > 0 {
+                    line += format!(", {} errors", s.errors).as_str();
+                }
+                match self.mode {
+                    ProgressMode::Bar => eprintln!("\r\x1b[2K{}", line),
+                    _ => eprintln!("{}", line),
+                }
+            }
+        }
+    }
+}
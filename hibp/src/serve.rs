@@ -0,0 +1,69 @@
+use std::io;
+use std::io::{BufReader, prelude::*};
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+
+use hibp_core::coverage::Lookup;
+use hibp_core::reader::Reader;
+use hibp_core::residency::IndexOptions;
+use hibp_core::HASH;
+
+fn respond(stream: &mut TcpStream, status: &str, body: &str) -> io::Result<()> {
+    write!(stream, "HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", status, body.len(), body)?;
+    return stream.flush();
+}
+
+fn parse_hash(path: &str) -> Option<HASH> {
+    let hex = path.strip_prefix("/hash/")?;
+    let mut hash: HASH = [0u8; 16];
+    hex::decode_to_slice(hex, &mut hash).ok()?;
+    return Some(hash);
+}
+
+// One request per connection: `GET /hash/<32 hex digits>` answers 200 when the NTLM hash is
+// pwned, 404 when it is not and 421 when its prefix is outside the index coverage.
+fn handle(index: &Reader, stream: TcpStream) -> io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut request = String::new();
+    reader.read_line(&mut request)?;
+    let mut stream = stream;
+
+    let mut parts = request.split_whitespace();
+    let hash = match (parts.next(), parts.next()) {
+        (Some("GET"), Some(path)) => parse_hash(path),
+        _ => None,
+    };
+
+    match hash {
+        Some(hash) => match index.lookup(&hash) {
+            Lookup::Found => respond(&mut stream, "200 OK", "pwned\n"),
+            Lookup::NotFound => respond(&mut stream, "404 Not Found", "not pwned\n"),
+            Lookup::NotCovered => respond(&mut stream, "421 Misdirected Request", "not covered\n"),
+        },
+        None => respond(&mut stream, "400 Bad Request", "expected GET /hash/<ntlm hex>\n"),
+    }
+}
+
+pub fn serve(dbdir: String, options: IndexOptions, listen: &str) -> io::Result<i32> {
+    let index = Reader::open(dbdir, options)?;
+
+    let listener = TcpListener::bind(listen)?;
+    println!("listening on {}", listener.local_addr()?);
+
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(v) => v,
+            Err(e) => {
+                eprintln!("accept: {}", e);
+                continue;
+            }
+        };
+        let index = index.clone();
+        thread::spawn(move || {
+            if let Err(e) = handle(&index, stream) {
+                eprintln!("connection: {}", e);
+            }
+        });
+    }
+    return Ok(0);
+}
@@ -1,4 +1,5 @@
-use std::cmp::min;
+#![allow(clippy::needless_return)]
+
 use std::collections::HashMap;
 use std::fs::File;
 use std::io::{Read, Write};
@@ -9,12 +10,14 @@ use md4::{Digest, Md4};
 use rand::{Rng, RngCore, SeedableRng};
 use thousands::Separable;
 use clap::Parser;
-use hibp_core::db::HIBPDB;
+use hibp_core::db::{SearchStrategy, HIBPDB};
+use hibp_core::eytzinger::EytzingerSearch;
+use hibp_core::residency::IndexOptions;
 
 pub fn timeit<F>(min_runtime: Duration, mut inner: F) -> u64
     where F: FnMut(),
 {
-    let mut rate = 0u64;
+    let mut rate;
     let mut loopit = 1;
 
     let total = Instant::now();
@@ -29,9 +32,7 @@ pub fn timeit<F>(min_runtime: Duration, mut inner: F) -> u64
         let elapsed = start.elapsed().as_secs_f64();
         rate = (loopit as f64 / elapsed) as u64;
 
-        if elapsed > min_runtime.as_secs_f64() {
-            break;
-        } else if total.elapsed().as_secs_f64() > 2.0*min_runtime.as_secs_f64() {
+        if elapsed > min_runtime.as_secs_f64() || total.elapsed().as_secs_f64() > 2.0*min_runtime.as_secs_f64() {
             break;
         }
         loopit = (min_runtime.as_secs_f64()/elapsed) as u64;
@@ -53,9 +54,13 @@ struct Args {
 }
 
 
+type BenchmarkFactory = Box<dyn Fn(&Args) -> Box<dyn FnMut()>>;
+
 struct BenchmarkJob {
     name: String,
-    func: Box<dyn Fn(&Args) -> Box<dyn FnMut()>>,
+    func: BenchmarkFactory,
+    // Operations per call of the closure; the reported rate is in operations per second.
+    ops: u64,
 }
 struct Benchmarker {
     job: HashMap<String, BenchmarkJob>,
@@ -65,6 +70,13 @@ struct Benchmarker {
 impl Benchmarker {
 
     fn register<F>(&mut self, name: &str, closure: F)
+    where
+        F: Fn(&Args) -> Box<dyn FnMut()> + 'static,
+    {
+        self.register_ops(name, 1, closure);
+    }
+
+    fn register_ops<F>(&mut self, name: &str, ops: u64, closure: F)
     where
         F: Fn(&Args) -> Box<dyn FnMut()> + 'static,
     {
@@ -73,6 +85,7 @@ impl Benchmarker {
         let job = BenchmarkJob {
             name: _name.clone(),
             func: Box::new(closure),
+            ops,
         };
 
         self.job.insert(_name, job);
@@ -87,7 +100,7 @@ impl Benchmarker {
         print!("{}: ", job.name);
         std::io::stdout().flush().unwrap();
         let inner = (job.func)(args);
-        let rate = timeit(min_runtime, inner);
+        let rate = timeit(min_runtime, inner) * job.ops;
         println!("{}", rate.separate_with_commas());
     }
 
@@ -104,6 +117,7 @@ impl Benchmarker {
 }
 
 const BUFFER_SIZE: usize = 1000;
+const PARALLEL_BATCH: usize = 100_000;
 
 fn main() {
     let args = Args::parse();
@@ -111,7 +125,7 @@ fn main() {
     let min_runtime = Duration::from_secs_f64(args.runtime);
     let mut b = Benchmarker{job: HashMap::new(), args: Args::parse()};
 
-    b.register("StdRng", |args| {
+    b.register("StdRng", |_args| {
         let mut rng = rand::rngs::StdRng::from_entropy();
 
         return Box::new(move || {
@@ -119,7 +133,7 @@ fn main() {
         });
     });
 
-    b.register("rng_bytes", |args| {
+    b.register("rng_bytes", |_args| {
         let item_size = 16;
         let mut pool: Vec<u8> = vec![0u8; item_size* BUFFER_SIZE];
         let threshold = pool.len();
@@ -140,7 +154,7 @@ fn main() {
         })
     });
 
-    b.register("rng_hash", |args| {
+    b.register("rng_hash", |_args| {
         let mut rng: RandomItemGenerator<HASH> = RandomItemGenerator::new(BUFFER_SIZE);
 
         return Box::new(move || {
@@ -148,44 +162,95 @@ fn main() {
         });
     });
 
-    b.register("utf8_to_utf16", |args| {
+    b.register("utf8_to_utf16", |_args| {
         return Box::new(move || {
             encode_to_utf16le("");
         });
     });
 
-    b.register("md4_crate", |args| {
+    b.register("md4_crate", |_args| {
         let raw: HASH = Default::default();
 
         return Box::new(move || {
             let mut hasher = Md4::new();
             md4::Digest::update(&mut hasher, raw);
-            let mut hash: &mut [u8; 16] = &mut Default::default();
+            let hash: &mut [u8; 16] = &mut Default::default();
             hash.copy_from_slice(hasher.finalize().as_slice());
         });
     });
 
-    b.register("dbquery_inmemory", |args| {
-        let mut db = HIBPDB::new(args.dbdirectory.clone()).unwrap();
-        let mut rng = RandomItemGenerator::new(BUFFER_SIZE);
+    b.register("utf8_to_utf16_md4_crate", |_args| {
+        let password = b"password".to_vec();
 
-        let mut index_slice: Vec<HASH> = Vec::new();
-        unsafe {
-            index_slice.reserve_exact(db.index().len());
-            index_slice.set_len(db.index().len());
+        return Box::new(move || {
+            let raw = encode_to_utf16le(std::str::from_utf8(password.as_slice()).unwrap());
+            let mut hasher = Md4::new();
+            md4::Digest::update(&mut hasher, raw);
+            let _: HASH = hasher.finalize().into();
+        });
+    });
+
+    b.register("hash_password", |_args| {
+        let mut hp = HashAndPassword {
+            hash: [0u8; 16],
+            password: b"password".to_vec(),
+        };
+
+        return Box::new(move || {
+            let _ = hash_password(&mut hp);
+        });
+    });
+
+    b.register("hash_password_batch16", |_args| {
+        let mut batch: Vec<HashAndPassword> = (0..16).map(|_| HashAndPassword {
+            hash: [0u8; 16],
+            password: b"password".to_vec(),
+        }).collect();
+        let mut valid = [false; 16];
+
+        return Box::new(move || {
+            hash_password_batch(batch.as_mut_slice(), &mut valid);
+        });
+    });
+
+    b.register("md4_fast_ntlm", |_args| {
+        return Box::new(move || {
+            let _ = md4_fast::ntlm("password");
+        });
+    });
+
+    // Each iteration hashes 16 passwords so the lane widths compare directly.
+    for isa in [md4_fast::Isa::Scalar, md4_fast::Isa::Sse2, md4_fast::Isa::Avx2, md4_fast::Isa::Avx512] {
+        if !isa.is_supported() {
+            continue;
         }
-        index_slice.copy_from_slice(db.index());
-        // let index_slice = db.index().clone();
+        let name = format!("md4_fast_batch16_{:?}", isa).to_lowercase();
+        b.register(name.as_str(), move |_args| {
+            let passwords = ["password"; 16];
+            let mut out = [[0u8; 16]; 16];
+
+            return Box::new(move || {
+                md4_fast::ntlm_batch_with(isa, &passwords, &mut out);
+            });
+        });
+    }
+
+    b.register("dbquery_inmemory", |args| {
+        let options = IndexOptions {
+            in_memory: true,
+            ..Default::default()
+        };
+        let db = HIBPDB::open(args.dbdirectory.clone(), options).unwrap();
+        let mut rng = RandomItemGenerator::new(BUFFER_SIZE);
 
         return Box::new(move || {
             let key = rng.next_item();
-            let _ = index_slice.binary_search(key);
-            // let _ = db.find(key);
+            let _ = db.index().binary_search(key);
         })
     });
 
     b.register("dbquery_miss_binary_search", |args| {
-        let mut db = HIBPDB::new(args.dbdirectory.clone()).unwrap();
+        let db = HIBPDB::new(args.dbdirectory.clone()).unwrap();
         let mut rng = RandomItemGenerator::new(BUFFER_SIZE);
 
         return Box::new(move || {
@@ -195,7 +260,7 @@ fn main() {
     });
 
     b.register("dbquery_miss_interpolation_search", |args| {
-        let mut db = HIBPDB::new(args.dbdirectory.clone()).unwrap();
+        let db = HIBPDB::new(args.dbdirectory.clone()).unwrap();
         let mut rng = RandomItemGenerator::new(BUFFER_SIZE);
 
         return Box::new(move || {
@@ -205,8 +270,28 @@ fn main() {
         })
     });
 
+    b.register("dbquery_miss_eytzinger_search", |args| {
+        let db = HIBPDB::new(args.dbdirectory.clone()).unwrap();
+        let mut rng = RandomItemGenerator::new(BUFFER_SIZE);
+
+        return Box::new(move || {
+            let key = rng.next_item();
+            let _ = db.eytzinger().eytzinger_search(key);
+        })
+    });
+
+    b.register("dbquery_miss_learned_search", |args| {
+        let db = HIBPDB::new(args.dbdirectory.clone()).unwrap();
+        let mut rng = RandomItemGenerator::new(BUFFER_SIZE);
+
+        return Box::new(move || {
+            let key = rng.next_item();
+            let _ = db.learned.as_ref().unwrap().search(db.index(), key);
+        })
+    });
+
     b.register("dbquery_hit_binary_search", |args| {
-        let mut db = HIBPDB::new(args.dbdirectory.clone()).unwrap();
+        let db = HIBPDB::new(args.dbdirectory.clone()).unwrap();
         let mut rng = RandomItemGenerator::<usize>::new(BUFFER_SIZE);
 
         return Box::new(move || {
@@ -218,7 +303,7 @@ fn main() {
     });
 
     b.register("dbquery_hit_interpolation_search", |args| {
-        let mut db = HIBPDB::new(args.dbdirectory.clone()).unwrap();
+        let db = HIBPDB::new(args.dbdirectory.clone()).unwrap();
         let mut rng = RandomItemGenerator::<usize>::new(BUFFER_SIZE);
 
         return Box::new(move || {
@@ -230,8 +315,60 @@ fn main() {
         })
     });
 
+    b.register("dbquery_hit_eytzinger_search", |args| {
+        let db = HIBPDB::new(args.dbdirectory.clone()).unwrap();
+        let mut rng = RandomItemGenerator::<usize>::new(BUFFER_SIZE);
+
+        return Box::new(move || {
+            let array = db.index();
+            let index = rng.next_item()%array.len();
+            let key: &HASH = &array[index];
+            let _ = db.eytzinger().eytzinger_search(key);
+        })
+    });
+
+    b.register("dbquery_hit_learned_search", |args| {
+        let db = HIBPDB::new(args.dbdirectory.clone()).unwrap();
+        let mut rng = RandomItemGenerator::<usize>::new(BUFFER_SIZE);
+
+        return Box::new(move || {
+            let array = db.index();
+            let index = rng.next_item()%array.len();
+            let key: &HASH = &array[index];
+            let _ = db.learned.as_ref().unwrap().search(array, key);
+        })
+    });
+
+    // The same shared HIBPDB queried from 1, 2, 4, ... threads up to the number of cores; each
+    // call runs PARALLEL_BATCH lookups per thread.
+    let cores = std::thread::available_parallelism().map(|v| v.get()).unwrap_or(1);
+    let mut threads = 1;
+    while threads <= cores {
+        let name = format!("dbquery_parallel_{}_threads", threads);
+        b.register_ops(name.as_str(), (threads*PARALLEL_BATCH) as u64, move |args| {
+            let mut db = HIBPDB::new(args.dbdirectory.clone()).unwrap();
+            db.set_strategy(SearchStrategy::Binary).unwrap();
+            let mut rng = RandomItemGenerator::<HASH>::new(BUFFER_SIZE);
+            let keys: Vec<HASH> = (0..threads*PARALLEL_BATCH).map(|_| *rng.next_item()).collect();
+
+            return Box::new(move || {
+                let db = &db;
+                std::thread::scope(|s| {
+                    for chunk in keys.chunks(PARALLEL_BATCH) {
+                        s.spawn(move || {
+                            for key in chunk {
+                                let _ = db.find(key);
+                            }
+                        });
+                    }
+                });
+            })
+        });
+        threads *= 2;
+    }
+
     b.register("range_extract", |args| {
-        let mut db = HIBPDB::new(args.dbdirectory.clone()).unwrap();
+        let db = HIBPDB::new(args.dbdirectory.clone()).unwrap();
         let mut rng = RandomItemGenerator::<usize>::new(BUFFER_SIZE);
 
         let map = db.range_map().unwrap();
@@ -243,11 +380,8 @@ fn main() {
             let pathname = db.dbdir.clone()+"/range/"+&map[idx];
             let mut fd = File::open(&pathname).unwrap();
             fd.read_to_end(&mut buff).unwrap();
-            if pathname.ends_with("xz") {
-                extract_xz(buff.as_slice()).unwrap();
-            } else {
-                extract_gz(buff.as_slice()).unwrap();
-            }
+            let codec = Codec::detect(&pathname, buff.as_slice()).unwrap();
+            codec.extract(buff.as_slice()).unwrap();
         })
     });
 
@@ -260,14 +394,3 @@ fn main() {
         }
     }
 }
-
-fn preload(arr: &[u8]) {
-    let mut buff = [0u8; 1<<16];
-
-    let mut off = 0;
-    while off < arr.len() {
-        let len = min(arr.len()-off, buff.len());
-        buff[0..len].copy_from_slice(&arr[off..off+len]);
-        off += len;
-    }
-}
@@ -0,0 +1,355 @@
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{ErrorKind, Read, Write};
+use std::os::unix::fs::FileExt;
+#[cfg(feature = "codecs")]
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+#[cfg(feature = "codecs")]
+use crate::db::HIBPDB;
+use crate::verify::RANGE_COUNT;
+use crate::{Codec, HashRange};
+
+pub const ARCHIVE_DATA: &str = "range.pack";
+pub const ARCHIVE_INDEX: &str = "range.idx";
+
+const MAGIC: &[u8; 8] = b"HIBPPACK";
+const VERSION: u32 = 2;
+const HEADER_SIZE: u64 = 24;
+const DATA_MAGIC: &[u8; 8] = b"HIBPDATA";
+const DATA_HEADER_SIZE: u64 = 16;
+const ENTRY_SIZE: usize = 32;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ArchiveEntry {
+    pub offset: u64,
+    pub length: u32,
+    pub codec: Option<Codec>,
+    pub etag: u64,
+    pub timestamp: i64,
+}
+
+impl ArchiveEntry {
+    fn codec_id(codec: Option<Codec>) -> u8 {
+        match codec {
+            None => 0,
+            Some(Codec::Raw) => 1,
+            Some(Codec::Gz) => 2,
+            Some(Codec::Xz) => 3,
+            Some(Codec::Zstd) => 4,
+        }
+    }
+
+    fn encode(&self) -> [u8; ENTRY_SIZE] {
+        let mut raw = [0u8; ENTRY_SIZE];
+        raw[0..8].copy_from_slice(&self.offset.to_le_bytes());
+        raw[8..12].copy_from_slice(&self.length.to_le_bytes());
+        raw[12] = Self::codec_id(self.codec);
+        raw[16..24].copy_from_slice(&self.etag.to_le_bytes());
+        raw[24..32].copy_from_slice(&self.timestamp.to_le_bytes());
+        return raw;
+    }
+
+    fn decode(raw: &[u8]) -> io::Result<Self> {
+        let codec = match raw[12] {
+            0 => None,
+            1 => Some(Codec::Raw),
+            2 => Some(Codec::Gz),
+            3 => Some(Codec::Xz),
+            4 => Some(Codec::Zstd),
+            v => return Err(io::Error::new(ErrorKind::InvalidData, format!("unknown codec id {}", v))),
+        };
+
+        Ok(Self {
+            offset: u64::from_le_bytes(raw[0..8].try_into().unwrap()),
+            length: u32::from_le_bytes(raw[8..12].try_into().unwrap()),
+            codec,
+            etag: u64::from_le_bytes(raw[16..24].try_into().unwrap()),
+            timestamp: i64::from_le_bytes(raw[24..32].try_into().unwrap()),
+        })
+    }
+
+    pub fn is_present(&self) -> bool {
+        return self.codec.is_some();
+    }
+
+    pub fn filename(&self, range: u32) -> String {
+        let ext = self.codec.map(|v| v.extension()).unwrap_or("");
+        return format!("{:05X}_{:016X}.{}", range, self.etag, ext);
+    }
+}
+
+// One append-only data file holding every range's compressed bytes, plus a fixed size table
+// with one entry per 20-bit prefix. Replacing a range appends the new bytes and rewrites only
+// its table entry, so the data file accumulates garbage until it is rebuilt with `pack`.
+// `open` only reads; the files are reopened for writing by the first `write`. Both files carry
+// the generation `create` picked, so a data file paired with another pack's table (e.g. after a
+// crash between the two renames in `pack`) is refused at `open`.
+pub struct RangeArchive {
+    pub data: File,
+    pub index: File,
+    pub entries: Vec<ArchiveEntry>,
+    path_data: String,
+    path_index: String,
+    writable: bool,
+}
+
+impl RangeArchive {
+
+    pub fn exists(dbdir: &str) -> bool {
+        return fs::metadata(String::from(dbdir)+"/"+ARCHIVE_INDEX).is_ok();
+    }
+
+    pub fn create(dbdir: &str) -> io::Result<Self> {
+        return Self::create_at(
+            (String::from(dbdir)+"/"+ARCHIVE_DATA).as_str(),
+            (String::from(dbdir)+"/"+ARCHIVE_INDEX).as_str(),
+        );
+    }
+
+    pub fn create_at(path_data: &str, path_index: &str) -> io::Result<Self> {
+        let mut data = OpenOptions::new().create(true).read(true).write(true).truncate(true)
+            .open(path_data)?;
+        let mut index = OpenOptions::new().create(true).read(true).write(true).truncate(true)
+            .open(path_index)?;
+
+        let generation: u64 = rand::random();
+        data.write_all(DATA_MAGIC)?;
+        data.write_all(&generation.to_le_bytes())?;
+
+        index.write_all(MAGIC)?;
+        index.write_all(&VERSION.to_le_bytes())?;
+        index.write_all(&(RANGE_COUNT as u32).to_le_bytes())?;
+        index.write_all(&generation.to_le_bytes())?;
+        index.set_len(HEADER_SIZE + (RANGE_COUNT*ENTRY_SIZE) as u64)?;
+
+        Ok(Self {
+            data,
+            index,
+            entries: vec![ArchiveEntry::default(); RANGE_COUNT],
+            path_data: String::from(path_data),
+            path_index: String::from(path_index),
+            writable: true,
+        })
+    }
+
+    pub fn open(dbdir: &str) -> io::Result<Self> {
+        let path_data = String::from(dbdir)+"/"+ARCHIVE_DATA;
+        let path_index = String::from(dbdir)+"/"+ARCHIVE_INDEX;
+        let data = File::open(&path_data)?;
+        let mut index = File::open(&path_index)?;
+
+        let mut raw: Vec<u8> = Vec::new();
+        index.read_to_end(&mut raw)?;
+        if raw.len() != HEADER_SIZE as usize + RANGE_COUNT*ENTRY_SIZE || &raw[0..8] != MAGIC {
+            return Err(io::Error::new(ErrorKind::InvalidData, "not a range archive"));
+        }
+        if u32::from_le_bytes(raw[8..12].try_into().unwrap()) != VERSION {
+            return Err(io::Error::new(ErrorKind::InvalidData, "unsupported range archive version"));
+        }
+        let mut header = [0u8; DATA_HEADER_SIZE as usize];
+        if data.read_exact_at(&mut header, 0).is_err() || &header[0..8] != DATA_MAGIC {
+            return Err(io::Error::new(ErrorKind::InvalidData, format!("{} is not range archive data", ARCHIVE_DATA)));
+        }
+        if header[8..16] != raw[16..24] {
+            return Err(io::Error::new(ErrorKind::InvalidData, format!("{} and {} are from different packs", ARCHIVE_DATA, ARCHIVE_INDEX)));
+        }
+
+        let mut entries: Vec<ArchiveEntry> = Vec::with_capacity(RANGE_COUNT);
+        for chunk in raw[HEADER_SIZE as usize..].chunks_exact(ENTRY_SIZE) {
+            entries.push(ArchiveEntry::decode(chunk)?);
+        }
+
+        Ok(Self {
+            data,
+            index,
+            entries,
+            path_data,
+            path_index,
+            writable: false,
+        })
+    }
+
+    pub fn get(&self, range: u32) -> Option<&ArchiveEntry> {
+        let entry = &self.entries[range as usize];
+        if entry.is_present() { Some(entry) } else { None }
+    }
+
+    pub fn read(&self, range: u32) -> io::Result<(Codec, Vec<u8>)> {
+        let entry = match self.get(range) {
+            Some(v) => v,
+            None => return Err(io::Error::new(ErrorKind::NotFound, format!("missing {:05X}", range))),
+        };
+
+        let mut buff = vec![0u8; entry.length as usize];
+        self.data.read_exact_at(buff.as_mut_slice(), entry.offset)?;
+        return Ok((entry.codec.unwrap(), buff));
+    }
+
+    fn reopen_writable(&mut self) -> io::Result<()> {
+        if !self.writable {
+            self.data = OpenOptions::new().read(true).write(true).open(&self.path_data)?;
+            self.index = OpenOptions::new().read(true).write(true).open(&self.path_index)?;
+            self.writable = true;
+        }
+        Ok(())
+    }
+
+    pub fn write(&mut self, hr: &HashRange) -> io::Result<()> {
+        self.reopen_writable()?;
+
+        let offset = self.data.metadata()?.len();
+        self.data.write_all_at(hr.compressed.as_slice(), offset)?;
+
+        let entry = ArchiveEntry {
+            offset,
+            length: hr.compressed.len() as u32,
+            codec: Some(hr.codec),
+            etag: hr.etag,
+            timestamp: hr.timestamp,
+        };
+        let pos = HEADER_SIZE + (hr.range as usize * ENTRY_SIZE) as u64;
+        self.index.write_all_at(&entry.encode(), pos)?;
+        self.entries[hr.range as usize] = entry;
+
+        Ok(())
+    }
+
+    // Clears the table entry of a range so it reads as missing; its bytes stay in the data file
+    // until the next `pack`.
+    pub fn remove(&mut self, range: u32) -> io::Result<()> {
+        self.reopen_writable()?;
+
+        let entry = ArchiveEntry::default();
+        let pos = HEADER_SIZE + (range as usize * ENTRY_SIZE) as u64;
+        self.index.write_all_at(&entry.encode(), pos)?;
+        self.entries[range as usize] = entry;
+
+        Ok(())
+    }
+
+    // `write` leaves durability to the caller, which syncs once after a batch of ranges.
+    pub fn sync(&self) -> io::Result<()> {
+        self.data.sync_data()?;
+        self.index.sync_data()?;
+        Ok(())
+    }
+}
+
+#[cfg(feature = "codecs")]
+fn archive_range(archive: &RangeArchive, range: u32, entry: &ArchiveEntry) -> io::Result<HashRange> {
+    let (codec, compressed) = archive.read(range)?;
+    Ok(HashRange {
+        range,
+        etag: entry.etag,
+        timestamp: entry.timestamp,
+        codec,
+        compressed,
+    })
+}
+
+#[cfg(feature = "codecs")]
+impl HIBPDB {
+
+    // Builds a fresh archive from the newest version of every range, taken from `range/` or
+    // from the current archive, using the same (modified time, etag) order as `gc`. The new
+    // archive is written next to the old one and only replaces it once complete; `range/` is
+    // left as is.
+    pub fn pack<F>(&mut self, mut f: F) -> io::Result<()> where F: FnMut(u32) {
+        let dir_range = self.dbdir.clone()+"/range/";
+        let files = match fs::metadata(&dir_range) {
+            Ok(_) => self.range_files()?,
+            Err(_) => vec![Vec::new(); RANGE_COUNT],
+        };
+
+        let path_data = self.dbdir.clone()+"/"+ARCHIVE_DATA;
+        let path_index = self.dbdir.clone()+"/"+ARCHIVE_INDEX;
+        let path_data_tmp = path_data.clone()+".tmp";
+        let path_index_tmp = path_index.clone()+".tmp";
+
+        let mut archive = RangeArchive::create_at(path_data_tmp.as_str(), path_index_tmp.as_str())?;
+        {
+            let current = self.archive()?.map(|v| v.read().unwrap());
+
+            for (range, mut names) in files.into_iter().enumerate() {
+                let range = range as u32;
+                names.sort_by_cached_key(|v| self.range_file_version(v.as_str()));
+                let newest_file = names.pop().map(|filename| {
+                    let (modified, etag) = self.range_file_version(filename.as_str());
+                    let timestamp = modified.duration_since(UNIX_EPOCH)
+                        .map(|v| v.as_secs() as i64)
+                        .unwrap_or(0);
+                    (filename, timestamp, etag)
+                });
+                let packed = current.as_ref().and_then(|v| v.get(range).copied());
+
+                let hr = match (newest_file, packed) {
+                    (None, None) => continue,
+                    (None, Some(entry)) => archive_range(current.as_ref().unwrap(), range, &entry)?,
+                    (Some((_, timestamp, etag)), Some(entry)) if (entry.timestamp, entry.etag) > (timestamp, etag) => {
+                        archive_range(current.as_ref().unwrap(), range, &entry)?
+                    },
+                    (Some((filename, timestamp, etag)), _) => {
+                        let compressed = fs::read(dir_range.clone()+filename.as_str())?;
+                        let codec = match Codec::detect(filename.as_str(), compressed.as_slice()) {
+                            Some(v) => v,
+                            None => return Err(io::Error::new(ErrorKind::InvalidInput, format!("unsupported file type: {}", filename))),
+                        };
+                        HashRange {
+                            range,
+                            etag,
+                            timestamp,
+                            codec,
+                            compressed,
+                        }
+                    },
+                };
+                archive.write(&hr)?;
+                f(range);
+            }
+        }
+        archive.sync()?;
+
+        fs::rename(path_data_tmp, &path_data)?;
+        fs::rename(path_index_tmp, &path_index)?;
+
+        // The new files were renamed over the old ones, so the archive paths follow them.
+        archive.path_data = path_data;
+        archive.path_index = path_index;
+        self.set_archive(archive);
+        Ok(())
+    }
+
+    pub fn unpack<F>(&self, mut f: F) -> io::Result<()> where F: FnMut(u32) {
+        let archive = match self.archive()? {
+            Some(v) => v.read().unwrap(),
+            None => return Err(io::Error::new(ErrorKind::NotFound, "range archive not found")),
+        };
+
+        let dir_range = self.dbdir.clone()+"/range/";
+        fs::create_dir_all(dir_range.clone())?;
+
+        for range in 0..RANGE_COUNT as u32 {
+            let entry = match archive.get(range) {
+                Some(v) => *v,
+                None => continue,
+            };
+            let (_, compressed) = archive.read(range)?;
+
+            let filename = entry.filename(range);
+            let path_tmp = dir_range.clone()+"tmp."+filename.as_str();
+            {
+                let mut fd = File::create(&path_tmp)?;
+                fd.write_all(compressed.as_slice())?;
+                if entry.timestamp > 0 {
+                    fd.set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(entry.timestamp as u64))?;
+                }
+            }
+            fs::rename(path_tmp, dir_range.clone()+filename.as_str())?;
+            f(range);
+        }
+
+        Ok(())
+    }
+}
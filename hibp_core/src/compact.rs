@@ -0,0 +1,187 @@
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{BufWriter, ErrorKind, Seek, SeekFrom, Write};
+
+use memmap2::Mmap;
+
+use crate::residency::IndexOptions;
+use crate::verify::{hash_prefix, RANGE_COUNT};
+use crate::HASH;
+
+pub const COMPACT_INDEX: &str = "index.compact";
+
+// The 20-bit prefix occupies the first two and a half bytes, so records start at byte 2 and
+// carry the low nibble of it along with the rest of the hash.
+pub const PREFIX_BYTES: usize = 2;
+pub const MAX_SUFFIX_BYTES: usize = 16 - PREFIX_BYTES;
+
+const MAGIC: &[u8; 8] = b"HIBPCIDX";
+const VERSION: u32 = 1;
+const HEADER_SIZE: usize = 24;
+const TABLE_SIZE: usize = (RANGE_COUNT+1)*8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IndexLayout {
+    Full,
+    Compact { suffix_bytes: usize },
+    Filter { bucket_bits: u32 },
+    Learned,
+}
+
+pub fn false_positive_rate(records: u64, suffix_bytes: usize) -> f64 {
+    if suffix_bytes >= MAX_SUFFIX_BYTES {
+        return 0.0;
+    }
+    let per_range = records as f64 / RANGE_COUNT as f64;
+    let bits = 8*suffix_bytes as i32 - 4;
+    return per_range * 2f64.powi(-bits);
+}
+
+pub struct CompactIndexWriter {
+    out: BufWriter<File>,
+    suffix_bytes: usize,
+    offsets: Vec<u64>,
+    count: u64,
+}
+
+impl CompactIndexWriter {
+    pub fn create(pathname: &str, suffix_bytes: usize) -> io::Result<Self> {
+        if suffix_bytes == 0 || suffix_bytes > MAX_SUFFIX_BYTES {
+            return Err(io::Error::new(ErrorKind::InvalidInput, format!("suffix_bytes must be within 1..={}", MAX_SUFFIX_BYTES)));
+        }
+
+        let fd = OpenOptions::new().create(true).write(true).truncate(true).open(pathname)?;
+        let mut out = BufWriter::new(fd);
+        out.write_all(&vec![0u8; HEADER_SIZE+TABLE_SIZE])?;
+
+        Ok(Self {
+            out,
+            suffix_bytes,
+            offsets: vec![0u64],
+            count: 0,
+        })
+    }
+
+    // Ranges must be pushed in ascending order, each as the packed 16 byte hashes that
+    // `extract_range` produces; ranges that are skipped end up empty.
+    pub fn push_range(&mut self, range: u32, hashes: &[u8]) -> io::Result<()> {
+        while self.offsets.len() <= range as usize {
+            self.offsets.push(self.count);
+        }
+        for hash in hashes.chunks_exact(16) {
+            self.out.write_all(&hash[PREFIX_BYTES..PREFIX_BYTES+self.suffix_bytes])?;
+            self.count += 1;
+        }
+        self.offsets.push(self.count);
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> io::Result<()> {
+        while self.offsets.len() <= RANGE_COUNT {
+            self.offsets.push(self.count);
+        }
+
+        let mut header: Vec<u8> = Vec::with_capacity(HEADER_SIZE+TABLE_SIZE);
+        header.extend(MAGIC);
+        header.extend(VERSION.to_le_bytes());
+        header.extend((self.suffix_bytes as u32).to_le_bytes());
+        header.extend(self.count.to_le_bytes());
+        for v in &self.offsets {
+            header.extend(v.to_le_bytes());
+        }
+
+        self.out.seek(SeekFrom::Start(0))?;
+        self.out.write_all(header.as_slice())?;
+        self.out.flush()?;
+        Ok(())
+    }
+}
+
+pub struct CompactIndex {
+    pub mmap: Mmap,
+    pub suffix_bytes: usize,
+    pub records: u64,
+}
+
+impl CompactIndex {
+    pub fn open(pathname: &str, options: &IndexOptions) -> io::Result<Self> {
+        let fd = File::open(pathname)?;
+        let mmap = options.map(&fd)?;
+
+        if mmap.len() < HEADER_SIZE+TABLE_SIZE || &mmap[0..8] != MAGIC {
+            return Err(io::Error::new(ErrorKind::InvalidData, "not a compact index"));
+        }
+        if u32::from_le_bytes(mmap[8..12].try_into().unwrap()) != VERSION {
+            return Err(io::Error::new(ErrorKind::InvalidData, "unsupported compact index version"));
+        }
+        let suffix_bytes = u32::from_le_bytes(mmap[12..16].try_into().unwrap()) as usize;
+        if suffix_bytes == 0 || suffix_bytes > MAX_SUFFIX_BYTES {
+            return Err(io::Error::new(ErrorKind::InvalidData, format!("compact index suffix_bytes {} is outside 1..={}", suffix_bytes, MAX_SUFFIX_BYTES)));
+        }
+        let records = u64::from_le_bytes(mmap[16..24].try_into().unwrap());
+        let size = (records as usize).checked_mul(suffix_bytes)
+            .and_then(|v| v.checked_add(HEADER_SIZE+TABLE_SIZE));
+        if size != Some(mmap.len()) {
+            return Err(io::Error::new(ErrorKind::InvalidData, "compact index is truncated"));
+        }
+
+        let out = Self {
+            mmap,
+            suffix_bytes,
+            records,
+        };
+
+        // Lookups slice records between neighbouring offsets without bounds checks of their own.
+        if out.offset(0) != 0 || out.offset(RANGE_COUNT) != out.len() {
+            return Err(io::Error::new(ErrorKind::InvalidData, "compact index offset table does not match its records"));
+        }
+        if let Some(range) = (0..RANGE_COUNT).find(|v| out.offset(*v) > out.offset(*v+1)) {
+            return Err(io::Error::new(ErrorKind::InvalidData, format!("compact index offsets decrease at {:05X}", range)));
+        }
+
+        Ok(out)
+    }
+
+    #[inline]
+    fn offset(&self, range: usize) -> usize {
+        let off = HEADER_SIZE + 8*range;
+        return u64::from_le_bytes(self.mmap[off..off+8].try_into().unwrap()) as usize;
+    }
+
+    #[inline]
+    fn record(&self, i: usize) -> &[u8] {
+        let off = HEADER_SIZE + TABLE_SIZE + i*self.suffix_bytes;
+        return &self.mmap[off..off+self.suffix_bytes];
+    }
+
+    pub fn len(&self) -> usize {
+        return self.records as usize;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.records == 0;
+    }
+
+    pub fn false_positive_rate(&self) -> f64 {
+        return false_positive_rate(self.records, self.suffix_bytes);
+    }
+
+    // Positions are the same ones the full index would report, as long as no truncated
+    // suffixes collide.
+    pub fn find(&self, key: &HASH) -> Result<usize, usize> {
+        let range = hash_prefix(key) as usize;
+        let mut lo = self.offset(range);
+        let mut hi = self.offset(range+1);
+        let needle = &key[PREFIX_BYTES..PREFIX_BYTES+self.suffix_bytes];
+
+        while lo < hi {
+            let mid = lo + (hi-lo)/2;
+            match self.record(mid).cmp(needle) {
+                std::cmp::Ordering::Less => lo = mid+1,
+                std::cmp::Ordering::Greater => hi = mid,
+                std::cmp::Ordering::Equal => return Ok(mid),
+            }
+        }
+        Err(lo)
+    }
+}
@@ -0,0 +1,219 @@
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{ErrorKind, Write};
+
+use futures::stream::FuturesOrdered;
+use futures::StreamExt;
+
+use crate::compact::{CompactIndexWriter, IndexLayout, COMPACT_INDEX};
+use crate::coverage::COVERAGE_FILE;
+use crate::db::{FullIndexWriter, IndexSink, HIBPDB};
+use crate::filter::{snapshot_id, FilterWriter, FILTER_INDEX};
+use crate::eytzinger::EYTZINGER_INDEX;
+use crate::learned::{LearnedModelWriter, LEARNED_MODEL};
+use crate::progress::{Progress, ProgressEvent};
+#[cfg(feature = "blocking")]
+use crate::progress::RangeCallback;
+use crate::shard::{shard_filename, split_prefixes, ShardManifest, ShardWriter, SHARD_MANIFEST};
+use crate::Codec;
+
+fn recompress_file(dir_range: &str, filename: &str, codec: Codec) -> io::Result<String> {
+    let pathname = String::from(dir_range)+filename;
+    let compressed = fs::read(&pathname)?;
+    let from = match Codec::detect(filename, compressed.as_slice()) {
+        Some(v) => v,
+        None => return Err(io::Error::new(ErrorKind::InvalidInput, format!("unsupported file type: {}", filename))),
+    };
+    if from == codec && filename.ends_with(codec.extension()) {
+        return Ok(String::from(filename));
+    }
+
+    let plain = from.extract(compressed.as_slice())?;
+    let target = format!("{}.{}", &filename[0..filename.rfind('.').unwrap_or(filename.len())], codec.extension());
+    let modified = fs::metadata(&pathname)?.modified()?;
+
+    let path_tmp = String::from(dir_range)+"tmp."+target.as_str();
+    {
+        let mut fd = File::create(&path_tmp)?;
+        fd.write_all(codec.compress(plain.as_slice())?.as_slice())?;
+        fd.set_modified(modified)?;
+    }
+    fs::rename(path_tmp, String::from(dir_range)+target.as_str())?;
+    fs::remove_file(pathname)?;
+
+    return Ok(target);
+}
+
+impl HIBPDB {
+
+    // Converts every stored range to `codec`, leaving files that already use it untouched.
+    #[cfg(feature = "blocking")]
+    pub fn recompress<F>(&self, codec: Codec, f: F) -> io::Result<()> where F: FnMut(u32) + Send {
+        return self.runtime().block_on(self.recompress_async(codec, f));
+    }
+
+    pub async fn recompress_async<F>(&self, codec: Codec, mut f: F) -> io::Result<()> where F: FnMut(u32) + Send {
+        let dir_range = self.dbdir.clone()+"/range/";
+        let files = self.range_files()?;
+
+        let mut pending = files.into_iter()
+            .enumerate()
+            .flat_map(|(range, v)| v.into_iter().map(move |filename| (range as u32, filename)));
+
+        let mut queue = FuturesOrdered::new();
+        let limit = 2*num_cpus::get();
+
+        loop {
+            if queue.len() < limit {
+                if let Some((range, filename)) = pending.next() {
+                    let dir = dir_range.clone();
+                    queue.push_back(tokio::task::spawn_blocking(move || {
+                        recompress_file(dir.as_str(), filename.as_str(), codec).map(|_| range)
+                    }));
+                    continue;
+                }
+            }
+
+            match queue.next().await {
+                Some(result) => f(result.map_err(io::Error::other)??),
+                None => break,
+            }
+        }
+
+        Ok(())
+    }
+
+    #[cfg(feature = "blocking")]
+    pub fn construct_index<F>(&mut self, f: F) -> io::Result<()> where F: FnMut(u32) + Send {
+        return self.construct_index_with(&mut RangeCallback(f));
+    }
+
+    #[cfg(feature = "blocking")]
+    pub fn construct_index_with(&mut self, progress: &mut (dyn Progress + Send)) -> io::Result<()> {
+        self.runtime().block_on(self.write_index(progress))?;
+        return self.reload();
+    }
+
+    // The new files replace the old ones by rename, so the mappings this db holds stay valid
+    // until `reload` swaps them for the new index.
+    pub async fn construct_index_async(&mut self, progress: &mut (dyn Progress + Send)) -> io::Result<()> {
+        self.write_index(progress).await?;
+        return self.reload();
+    }
+
+    async fn write_index(&self, progress: &mut (dyn Progress + Send)) -> io::Result<()> {
+        let map = self.range_map()?;
+
+        let file_index = self.dbdir.clone()+"/index.bin";
+        let file_manifest = self.dbdir.clone()+"/"+SHARD_MANIFEST;
+        if self.shard_count > 1 && self.layout != IndexLayout::Full {
+            return Err(io::Error::new(ErrorKind::InvalidInput, "only the full layout can be sharded"));
+        }
+        let shards = split_prefixes(self.prefixes, self.shard_count);
+        let previous = match fs::metadata(&file_manifest) {
+            Ok(_) => Some(ShardManifest::load(file_manifest.as_str())?),
+            Err(_) => None,
+        };
+
+        // Every file is written as `<name>.tmp` and renamed into place once all sinks finished.
+        let tmp = |pathname: &str| String::from(pathname)+".tmp";
+        let mut written: Vec<String> = Vec::new();
+        let mut sinks: Vec<Box<dyn IndexSink + Send>> = match self.layout {
+            IndexLayout::Full if self.shard_count > 1 => {
+                written.extend((0..shards.len()).map(|i| self.dbdir.clone()+"/"+shard_filename(i).as_str()));
+                written.push(file_manifest.clone());
+                vec![Box::new(ShardWriter::create(self.dbdir.as_str(), &shards)?)]
+            }
+            IndexLayout::Full => {
+                written.push(file_index.clone());
+                vec![Box::new(FullIndexWriter::create(tmp(&file_index).as_str())?)]
+            }
+            IndexLayout::Compact { suffix_bytes } => {
+                written.push(self.dbdir.clone()+"/"+COMPACT_INDEX);
+                vec![Box::new(CompactIndexWriter::create(tmp(&written[0]).as_str(), suffix_bytes)?)]
+            }
+            IndexLayout::Filter { bucket_bits } => {
+                written.push(self.dbdir.clone()+"/"+FILTER_INDEX);
+                vec![Box::new(FilterWriter::create(tmp(&written[0]).as_str(), bucket_bits, snapshot_id(&map))?)]
+            }
+            // The model predicts positions in index.bin, so both are written from the same pass.
+            IndexLayout::Learned => {
+                written.push(file_index.clone());
+                written.push(self.dbdir.clone()+"/"+LEARNED_MODEL);
+                vec![
+                    Box::new(FullIndexWriter::create(tmp(&written[0]).as_str())?),
+                    Box::new(LearnedModelWriter::create(tmp(&written[1]).as_str())?),
+                ]
+            }
+        };
+
+        // Sinks treat ranges that are never pushed as empty.
+        let (start, end) = (self.prefixes.start, self.prefixes.end);
+        progress.event(ProgressEvent::Started { task: "construct", total: self.prefixes.count() as u64 });
+        let mut queue = FuturesOrdered::new();
+        let limit = 1000;
+
+        let mut wp = start;
+        let mut rp = start;
+        while rp <= end {
+            if wp <= end && queue.len() < limit {
+                let fut = self.extract_range(&map, wp);
+                queue.push_back(fut);
+                wp += 1;
+            } else {
+                let buff = match queue.next().await {
+                    Some(v) => v?,
+                    None => break,
+                };
+                for sink in sinks.iter_mut() {
+                    sink.push_range(rp, buff.as_slice())?;
+                }
+                progress.event(ProgressEvent::Advanced { range: rp, bytes: buff.len() as u64 });
+                rp += 1;
+            }
+        }
+
+        for sink in sinks {
+            sink.finish()?;
+        }
+        // The shard manifest is renamed last, after the shard files it lists.
+        for pathname in &written {
+            fs::rename(tmp(pathname), pathname)?;
+        }
+
+        // Shard files of the previous layout that the new one did not overwrite.
+        if let (Some(previous), true) = (previous, matches!(self.layout, IndexLayout::Full | IndexLayout::Learned)) {
+            for shard in previous.shards {
+                let pathname = self.dbdir.clone()+"/"+shard.filename.as_str();
+                if !written.contains(&pathname) && fs::metadata(&pathname).is_ok() {
+                    fs::remove_file(&pathname)?;
+                }
+            }
+        }
+        // index.bin and a shard manifest are alternatives; the one not built is stale.
+        let stale = match self.shard_count > 1 {
+            true => file_index,
+            false => file_manifest,
+        };
+        if fs::metadata(&stale).is_ok() && matches!(self.layout, IndexLayout::Full | IndexLayout::Learned) {
+            fs::remove_file(&stale)?;
+        }
+        // Files derived from index.bin describe the old one now that it was replaced.
+        let derived: &[&str] = match self.layout {
+            IndexLayout::Full => &[EYTZINGER_INDEX, LEARNED_MODEL],
+            IndexLayout::Learned => &[EYTZINGER_INDEX],
+            _ => &[],
+        };
+        for name in derived {
+            let pathname = self.dbdir.clone()+"/"+name;
+            if fs::metadata(&pathname).is_ok() {
+                fs::remove_file(&pathname)?;
+            }
+        }
+
+        self.prefixes.save((self.dbdir.clone()+"/"+COVERAGE_FILE).as_str())?;
+        progress.event(ProgressEvent::Finished);
+        Ok(())
+    }
+}
@@ -0,0 +1,80 @@
+use std::fs;
+use std::io;
+use std::io::ErrorKind;
+use std::str::FromStr;
+
+use crate::verify::RANGE_COUNT;
+
+// Written next to the index by `construct_index`; an index without one covers every prefix.
+pub const COVERAGE_FILE: &str = "index.coverage";
+
+// Inclusive interval of 5 hex digit prefixes, written `00000-3FFFF` or `ABCDE` for one range.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PrefixRange {
+    pub start: u32,
+    pub end: u32,
+}
+
+impl PrefixRange {
+    pub const FULL: PrefixRange = PrefixRange { start: 0, end: RANGE_COUNT as u32 - 1 };
+
+    pub fn contains(&self, range: u32) -> bool {
+        return self.start <= range && range <= self.end;
+    }
+
+    pub fn count(&self) -> usize {
+        return (self.end - self.start) as usize + 1;
+    }
+
+    pub fn is_full(&self) -> bool {
+        return *self == Self::FULL;
+    }
+
+    pub fn load(pathname: &str) -> io::Result<Self> {
+        let text = fs::read_to_string(pathname)?;
+        return text.trim().parse().map_err(|e| io::Error::new(ErrorKind::InvalidData, e));
+    }
+
+    pub fn save(&self, pathname: &str) -> io::Result<()> {
+        return fs::write(pathname, format!("{}\n", self));
+    }
+}
+
+impl Default for PrefixRange {
+    fn default() -> Self {
+        return Self::FULL;
+    }
+}
+
+impl std::fmt::Display for PrefixRange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:05X}-{:05X}", self.start, self.end)
+    }
+}
+
+impl FromStr for PrefixRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let prefix = |v: &str| match v.trim() {
+            t if t.len() == 5 => u32::from_str_radix(t, 16).map_err(|e| format!("invalid prefix range {:?}: {}", s, e)),
+            _ => Err(format!("invalid prefix range {:?}: prefixes have 5 hex digits", s)),
+        };
+        let (start, end) = match s.split_once('-') {
+            Some((start, end)) => (prefix(start)?, prefix(end)?),
+            None => (prefix(s)?, prefix(s)?),
+        };
+        if start > end {
+            return Err(format!("invalid prefix range {:?}: start after end", s));
+        }
+        Ok(Self { start, end })
+    }
+}
+
+// `NotCovered` means the index was built without the hash's range, so a miss says nothing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Lookup {
+    Found,
+    NotFound,
+    NotCovered,
+}
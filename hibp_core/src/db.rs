@@ -1,257 +1,499 @@
 use std::{fs, io};
 use std::fs::{File, OpenOptions};
-use std::io::{BufRead, ErrorKind, Read, Write};
+use std::io::{BufWriter, ErrorKind, Write};
+use std::marker::PhantomData;
 use std::mem::size_of;
-use memmap2::{MmapMut, MmapOptions};
-use crate::{dir_list, download_range, extract_gz, extract_xz, HASH, HashRange, InterpolationSearch};
-use bit_set::BitSet;
-
-use futures::stream::{FuturesOrdered, FuturesUnordered};
-use futures::StreamExt;
-use regex::Regex;
-
-pub struct FileArray<'a, T> {
+use std::sync::OnceLock;
+use std::sync::RwLock;
+use memmap2::{Mmap, MmapOptions};
+use crate::archive::RangeArchive;
+use crate::coverage::{Lookup, PrefixRange, COVERAGE_FILE};
+use crate::compact::{CompactIndex, CompactIndexWriter, IndexLayout, COMPACT_INDEX};
+use crate::eytzinger::EYTZINGER_INDEX;
+use crate::filter::{FilterIndex, FILTER_INDEX};
+use crate::learned::{LearnedIndex, LEARNED_MODEL};
+use crate::residency::IndexOptions;
+use crate::shard::{ShardManifest, ShardSet, SHARD_MANIFEST};
+use crate::verify::hash_prefix;
+use crate::{Codec, HASH, InterpolationSearch};
+
+#[cfg(feature = "async")]
+use futures::{Stream, StreamExt};
+
+// A read-only view of a file of `T` records. The mapping is never written through, so one
+// FileArray can be read from any number of threads.
+pub struct FileArray<T> {
     pub pathname: String,
     pub fd: File,
-    pub mmap: MmapMut,
-    pub slice: &'a mut [T],
+    pub mmap: Mmap,
+    records: PhantomData<T>,
 }
 
-impl<'a, T> FileArray<'a, T> {
+impl<T> FileArray<T> {
 
-    pub fn new(_pathname: String, size: usize) -> std::io::Result<Self> {
-        let fd = fs::OpenOptions::new()
-            .create(true)
-            .write(true)
-            .append(true)
-            .open(_pathname.clone())?;
+    // Writes `len` records through a temporary writable mapping of `<pathname>.tmp`, then
+    // renames it over `pathname` and maps it read-only. Existing mappings of the old file stay
+    // valid.
+    pub fn create<F>(pathname: String, len: usize, options: &IndexOptions, fill: F) -> io::Result<Self> where F: FnOnce(&mut [T]) {
+        let path_tmp = pathname.clone()+".tmp";
+        {
+            let fd = OpenOptions::new()
+                .create(true)
+                .truncate(true)
+                .read(true)
+                .write(true)
+                .open(&path_tmp)?;
+            fd.set_len((len * size_of::<T>()) as u64)?;
+
+            let mut mmap = unsafe { MmapOptions::new().map_mut(&fd)? };
+            let slice = unsafe {
+                std::slice::from_raw_parts_mut(mmap.as_mut_ptr() as *mut T, mmap.len()/size_of::<T>())
+            };
+            fill(slice);
+            mmap.flush()?;
+        }
+        fs::rename(path_tmp, &pathname)?;
+        return Self::open_with(pathname, options);
+    }
 
-        fd.set_len((size * size_of::<T>()) as u64)?;
+    pub fn open(_pathname: String) -> std::io::Result<Self> {
+        return Self::open_with(_pathname, &IndexOptions::default());
+    }
 
-        let mut mmap_mut = unsafe { MmapOptions::new().map_mut(&fd)? };
+    pub fn open_with(_pathname: String, options: &IndexOptions) -> std::io::Result<Self> {
+        let fd = File::open(_pathname.clone())?;
 
-        let slice = unsafe {
-            let ptr = mmap_mut.as_mut_ptr() as *mut T;
-            std::slice::from_raw_parts_mut(ptr, mmap_mut.len()/size_of::<T>())
-        };
+        let mmap = options.map(&fd)?;
 
         Ok(Self {
             pathname: _pathname,
             fd,
-            mmap: mmap_mut,
-            slice,
+            mmap,
+            records: PhantomData,
         })
     }
 
-    pub fn as_mut_slice(&mut self) -> &mut [T] {
-        return self.slice;
-    }
-
+    // Borrowed from `self`, so the records can not outlive the mapping.
     pub fn as_slice(&self) -> &[T] {
-        return self.slice;
+        return unsafe {
+            std::slice::from_raw_parts(self.mmap.as_ptr() as *const T, self.len())
+        };
     }
 
+    // Number of `T` records, not bytes; the file size is `len() * size_of::<T>()`.
     pub fn len(&self) -> usize {
-        return self.mmap.len();
+        return self.mmap.len()/size_of::<T>();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.len() == 0;
+    }
+
+}
+
+// Answers `keys` in sorted order so consecutive lookups stay within one shard and nearby
+// pages, then returns the results in the order of `keys`.
+fn in_key_order<T: Clone, F: FnMut(&HASH) -> T>(keys: &[HASH], empty: T, mut f: F) -> Vec<T> {
+    let mut order: Vec<usize> = (0..keys.len()).collect();
+    order.sort_unstable_by_key(|i| keys[*i]);
+
+    let mut out: Vec<T> = vec![empty; keys.len()];
+    for i in order {
+        out[i] = f(&keys[i]);
+    }
+    return out;
+}
+
+pub const LOOKUP_CHUNK: usize = 4096;
+
+pub const RANGE_FILENAME: &str = "^([0-9a-fA-F]{5})_([0-9a-fA-F]{16})\\.(gz|xz|zst|txt)$";
+
+pub trait IndexSink {
+    fn push_range(&mut self, range: u32, hashes: &[u8]) -> io::Result<()>;
+    fn finish(self: Box<Self>) -> io::Result<()>;
+}
+
+pub struct FullIndexWriter {
+    out: BufWriter<File>,
+}
+
+impl FullIndexWriter {
+    pub fn create(pathname: &str) -> io::Result<Self> {
+        let fd = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(pathname)?;
+
+        Ok(Self {
+            out: BufWriter::new(fd),
+        })
+    }
+}
+
+impl IndexSink for FullIndexWriter {
+    fn push_range(&mut self, _range: u32, hashes: &[u8]) -> io::Result<()> {
+        self.out.write_all(hashes)
     }
 
+    fn finish(mut self: Box<Self>) -> io::Result<()> {
+        self.out.flush()
+    }
 }
 
+impl IndexSink for CompactIndexWriter {
+    fn push_range(&mut self, range: u32, hashes: &[u8]) -> io::Result<()> {
+        CompactIndexWriter::push_range(self, range, hashes)
+    }
+
+    fn finish(self: Box<Self>) -> io::Result<()> {
+        CompactIndexWriter::finish(*self)
+    }
+}
 
+// Every strategy reports positions in index.bin. Eytzinger and Learned need their file next to
+// index.bin; `open` picks the first of them that matches index.bin, Interpolation otherwise.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SearchStrategy {
+    Binary,
+    Interpolation,
+    Eytzinger,
+    Learned,
+}
 
-pub struct HIBPDB<'a> {
+pub struct HIBPDB {
     pub dbdir: String,
-    pub index: Option<FileArray<'a, HASH>>,
-    pub rt: tokio::runtime::Runtime,
+    pub index: Option<FileArray<HASH>>,
+    pub eytzinger: Option<FileArray<HASH>>,
+    pub compact: Option<CompactIndex>,
+    pub filter: Option<FilterIndex>,
+    pub learned: Option<LearnedIndex>,
+    pub layout: IndexLayout,
+    // construct_index splits a full index into this many shard files when above one.
+    pub shard_count: usize,
+    pub shards: Option<ShardSet>,
+    strategy: SearchStrategy,
+    pub options: IndexOptions,
+    pub codec: Codec,
+    // range.pack is opened on first use, so opening a db for lookups never touches it.
+    archive: OnceLock<Option<RwLock<RangeArchive>>>,
+    // `prefixes` limits what update and construct_index work on; `coverage` is what the index
+    // on disk was built from.
+    pub prefixes: PrefixRange,
+    pub coverage: PrefixRange,
+    // range/ as listed by the first `prevalence`, so lookups do not list it again per hash.
+    #[cfg(feature = "codecs")]
+    pub(crate) listing: OnceLock<Vec<Vec<String>>>,
+    // Only the blocking wrappers need a runtime of their own; it is started on first use.
+    #[cfg(feature = "blocking")]
+    rt: OnceLock<tokio::runtime::Runtime>,
 }
 
-impl<'a> HIBPDB<'a> {
+impl HIBPDB {
     pub fn new(v: String) -> std::io::Result<Self> {
+        return Self::open(v, IndexOptions::default());
+    }
+
+    pub fn open(v: String, options: IndexOptions) -> std::io::Result<Self> {
         let dbdir = v.clone();
         let mut file_index = dbdir.clone();
         file_index.push_str("/index.bin");
+        let file_eytzinger = dbdir.clone()+"/"+EYTZINGER_INDEX;
+        let file_manifest = dbdir.clone()+"/"+SHARD_MANIFEST;
 
-        Ok(Self {
-            dbdir,
-            index: None,
-            rt: tokio::runtime::Builder::new_multi_thread()
-                .enable_all()
-                .build()
-                .unwrap(),
-        })
-    }
-
-    pub fn save(self: &Self, hr: HashRange) -> std::io::Result<()> {
-        let prefix: String = self.dbdir.clone()+"/range/";
-        let fname = format!("{:05X}_{:016X}.{}", hr.range, hr.etag, HashRange::EXTENSION);
+        let file_compact = dbdir.clone()+"/"+COMPACT_INDEX;
+        let file_filter = dbdir.clone()+"/"+FILTER_INDEX;
+        let file_learned = dbdir.clone()+"/"+LEARNED_MODEL;
 
-        let path_tmp = prefix.clone()+"tmp."+fname.as_str();
-        let pathname = prefix+fname.as_str();
-        {
-            let mut fd = File::create(&path_tmp)?;
-            fd.write_all(hr.compressed.as_slice())?;
+        let mut resident = vec![file_index.clone(), file_eytzinger.clone(), file_compact.clone(), file_filter.clone(), file_learned.clone()];
+        if fs::metadata(&file_manifest).is_ok() {
+            let manifest = ShardManifest::load(file_manifest.as_str())?;
+            resident.extend(manifest.shards.iter().map(|v| dbdir.clone()+"/"+v.filename.as_str()));
         }
-        fs::rename(path_tmp, pathname)?;
+        options.check_budget(&resident)?;
 
-        Ok(())
-    }
+        let index = match fs::metadata(&file_index) {
+            Ok(_) => Some(FileArray::open_with(file_index, &options)?),
+            Err(_) => None,
+        };
 
-    pub fn update<F>(self: &Self, mut f: F) -> io::Result<()> where F: FnMut(u32)  {
-        let dir_range = self.dbdir.clone()+"/range/";
-        fs::create_dir_all(dir_range.clone()).unwrap();
+        let eytzinger = match fs::metadata(&file_eytzinger) {
+            Ok(_) => Some(FileArray::open_with(file_eytzinger, &options)?),
+            Err(_) => None,
+        };
 
-        let limit = 500;
-        let client = reqwest::Client::new();
+        let compact = match fs::metadata(&file_compact) {
+            Ok(_) => Some(CompactIndex::open(file_compact.as_str(), &options)?),
+            Err(_) => None,
+        };
 
-        let fut = async {
-            let mut queue = FuturesUnordered::new();
+        let filter = match fs::metadata(&file_filter) {
+            Ok(_) => Some(FilterIndex::open(file_filter.as_str(), &options)?),
+            Err(_) => None,
+        };
 
-            let ls = dir_list(dir_range.as_str()).unwrap();
-            let mut bs = BitSet::new();
-            for key in ls {
-                let t = u32::from_str_radix(&key[0..5], 16).unwrap();
-                bs.insert(t as usize);
-            }
+        let learned = match fs::metadata(&file_learned) {
+            Ok(_) => Some(LearnedIndex::open(file_learned.as_str(), &options)?),
+            Err(_) => None,
+        };
 
-            let mut i = 0u32;
-            loop {
-                if i<(1<<20) && queue.len() < limit {
-                    if !bs.contains(i as usize) {
-                        queue.push(download_range(&client, i));
-                    }
-                    i += 1;
-                    continue;
-                }
-
-                if let Some(result) = queue.next().await {
-                    match result {
-                        Ok(v) => {
-                            f(v.range);
-                            self.save(v).unwrap();
-                        }
-                        Err(err) => {
-                            queue.push(download_range(&client, err.range));
-                        }
-                    }
-                }
-
-                if i >= (1<<20) && queue.is_empty() {
-                    break;
-                }
-            }
+        let shards = match fs::metadata(&file_manifest) {
+            Ok(_) => Some(ShardSet::open(dbdir.as_str(), &options)?),
+            Err(_) => None,
         };
 
-        self.rt.block_on(fut);
+        let file_coverage = dbdir.clone()+"/"+COVERAGE_FILE;
+        let coverage = match fs::metadata(&file_coverage) {
+            Ok(_) => PrefixRange::load(file_coverage.as_str())?,
+            Err(_) => PrefixRange::FULL,
+        };
 
+        let mut db = Self {
+            dbdir,
+            index,
+            eytzinger,
+            compact,
+            filter,
+            learned,
+            layout: IndexLayout::Full,
+            shard_count: 1,
+            shards,
+            strategy: SearchStrategy::Interpolation,
+            options,
+            codec: Codec::Gz,
+            archive: OnceLock::new(),
+            prefixes: PrefixRange::FULL,
+            coverage,
+            #[cfg(feature = "codecs")]
+            listing: OnceLock::new(),
+            #[cfg(feature = "blocking")]
+            rt: OnceLock::new(),
+        };
+
+        if let Some(strategy) = [SearchStrategy::Eytzinger, SearchStrategy::Learned].into_iter().find(|v| db.check_strategy(*v).is_ok()) {
+            db.strategy = strategy;
+        }
+        Ok(db)
+    }
+
+    // Maps the index files again, e.g. after construct_index replaced them. What the db was
+    // told to build or store with (layout, prefixes, codec, ...) is kept.
+    pub fn reload(&mut self) -> io::Result<()> {
+        let db = Self::open(self.dbdir.clone(), self.options)?;
+        self.index = db.index;
+        self.eytzinger = db.eytzinger;
+        self.compact = db.compact;
+        self.filter = db.filter;
+        self.learned = db.learned;
+        self.shards = db.shards;
+        self.strategy = db.strategy;
+        self.coverage = db.coverage;
         Ok(())
     }
 
+    pub fn archive(&self) -> io::Result<Option<&RwLock<RangeArchive>>> {
+        if let Some(v) = self.archive.get() {
+            return Ok(v.as_ref());
+        }
+        let archive = match RangeArchive::exists(self.dbdir.as_str()) {
+            true => Some(RwLock::new(RangeArchive::open(self.dbdir.as_str())?)),
+            false => None,
+        };
+        // Another thread may have opened it first, in which case its copy is kept.
+        let _ = self.archive.set(archive);
+        return Ok(self.archive.get().unwrap().as_ref());
+    }
 
-    pub fn range_map(&self) -> io::Result<Vec<String>> {
-        let dir_range = self.dbdir.clone()+"/range/";
+    #[cfg(feature = "codecs")]
+    pub(crate) fn set_archive(&mut self, archive: RangeArchive) {
+        self.archive = OnceLock::from(Some(RwLock::new(archive)));
+    }
 
-        let mut ls = dir_list(dir_range.as_str()).unwrap();
-        ls.sort();
-        let re = Regex::new("^([0-9a-fA-F]{5})_([0-9a-fA-F]{16})\\..z$").unwrap();
+    // The `*_async` methods run on the caller's runtime. With the `blocking` feature the methods
+    // without the suffix drive them on a runtime owned by the database, so they must not be
+    // called from inside an async context.
+    #[cfg(feature = "blocking")]
+    pub fn runtime(&self) -> &tokio::runtime::Runtime {
+        return self.rt.get_or_init(|| {
+            tokio::runtime::Builder::new_multi_thread()
+                .enable_all()
+                .build()
+                .unwrap()
+        });
+    }
 
-        let mut out: Vec<String> = Vec::new();
 
-        let mut i = 0;
-        for filename in ls {
-            if ! re.is_match(&filename) {
-                continue;
-            }
-            let cap = re.captures(filename.as_str()).unwrap();
-            let range = u32::from_str_radix(cap.get(1).unwrap().as_str(), 16).unwrap();
-            if range > i {
-                return Err(io::Error::new(ErrorKind::NotFound, format!("missing {:05X}", i)));
-            } else if range < i {
-                return Err(io::Error::new(ErrorKind::AlreadyExists, format!("duplicate {:05X}", i)));
-            }
-            out.push(filename);
-            i += 1;
-        }
 
-        if i < (1<<20) {
-            return Err(io::Error::new(ErrorKind::NotFound, "not all ranges are present"));
-        }
+    #[inline]
+    pub fn index(&self) -> &[HASH] {
+        let fa: &FileArray<HASH> = self.index.as_ref().unwrap();
+        return fa.as_slice();
+    }
 
-        return Ok(out);
+    // Queries only read the mappings, so a HIBPDB can be shared between threads as is.
+    // Positions need an index holding every record; a filter-only db can answer `contains` but
+    // not `find`.
+    pub fn find(&self, key: &HASH) -> io::Result<Result<usize, usize>> {
+        self.check_positions()?;
+        self.check_covered(key)?;
+        return Ok(self.search(key));
     }
 
+    pub fn strategy(&self) -> SearchStrategy {
+        return self.strategy;
+    }
 
-    async fn extract_range(&self, range_map: &Vec<String>, range: u32) -> io::Result<Vec<u8>> {
-        let dir_range = self.dbdir.clone()+"/range/";
+    pub fn set_strategy(&mut self, strategy: SearchStrategy) -> io::Result<()> {
+        self.check_strategy(strategy)?;
+        self.strategy = strategy;
+        Ok(())
+    }
 
-        let mut buff: Vec<u8> = Vec::new();
-        let filename = &range_map[range as usize];
-        let mut fd = File::open(dir_range.clone()+"/"+filename.as_str())?;
-        fd.read_to_end(&mut buff)?;
+    fn check_strategy(&self, strategy: SearchStrategy) -> io::Result<()> {
+        let missing = |name: &str| io::Error::new(ErrorKind::NotFound, format!("{}/{} not found", self.dbdir, name));
+        // A layout file left over from an earlier index.bin would answer with wrong positions.
+        let stale = |name: &str| io::Error::new(ErrorKind::InvalidData, format!("{}/{} does not match index.bin", self.dbdir, name));
+        let records = match &self.index {
+            Some(fa) => fa.len(),
+            None => return Ok(()),
+        };
+        match (strategy, &self.eytzinger, &self.learned) {
+            (SearchStrategy::Eytzinger, None, _) => Err(missing(EYTZINGER_INDEX)),
+            (SearchStrategy::Eytzinger, Some(ey), _) if ey.len() != records+1 => Err(stale(EYTZINGER_INDEX)),
+            (SearchStrategy::Learned, _, None) => Err(missing(LEARNED_MODEL)),
+            (SearchStrategy::Learned, _, Some(model)) if model.records != records as u64 => Err(stale(LEARNED_MODEL)),
+            _ => Ok(()),
+        }
+    }
 
-        let plain = match HashRange::EXTENSION {
-            "xz" => extract_xz(buff.as_slice()),
-            "gz" => extract_gz(buff.as_slice()),
-            _ => return Err(io::Error::new(ErrorKind::InvalidInput, "unsupported file type")),
-        }?;
+    fn check_positions(&self) -> io::Result<()> {
+        if self.index.is_none() && self.shards.is_none() && self.compact.is_none() {
+            let msg = match self.filter {
+                Some(_) => "the filter index has no record positions",
+                None => "no index found",
+            };
+            return Err(io::Error::new(ErrorKind::Unsupported, format!("{}: {}", self.dbdir, msg)));
+        }
+        return self.check_strategy(self.strategy);
+    }
 
-        buff.clear();
-        let mut hash = vec![0u8; 16];
-        for v in plain.lines() {
-            let line = v?;
-            let t = hex::decode_to_slice(format!("{:05X}{}", range, &line[0..(32-5)]), hash.as_mut_slice());
-            match t {
-                Ok(_) => buff.extend(&hash),
-                Err(e) => return Err(io::Error::new(ErrorKind::InvalidInput, e.to_string())),
+    fn search(&self, key: &HASH) -> Result<usize, usize> {
+        if self.index.is_none() {
+            if let Some(shards) = &self.shards {
+                return shards.find(key);
             }
+            if let Some(compact) = &self.compact {
+                return compact.find(key);
+            }
+        }
+        // A partial-prefix construct can leave index.bin empty; interpolation divides by its length.
+        if self.index().is_empty() {
+            return Err(0);
         }
+        // `find` reports a missing layout file; membership falls back to index.bin.
+        match (self.strategy, &self.eytzinger, &self.learned) {
+            (SearchStrategy::Binary, _, _) => self.index().binary_search(key),
+            (SearchStrategy::Eytzinger, Some(_), _) => self.eytzinger_find(key),
+            (SearchStrategy::Learned, _, Some(model)) => model.search(self.index(), key),
+            _ => self.index().interpolation_search(key),
+        }
+    }
 
-        Ok(buff)
+    // Unlike `lookup`, a key outside the index coverage is an error rather than a miss.
+    pub fn contains(&self, key: &HASH) -> io::Result<bool> {
+        self.check_covered(key)?;
+        return Ok(self.lookup(key) == Lookup::Found);
     }
 
-    pub fn construct_index<F>(&self, mut f: F) -> io::Result<()> where F: FnMut(u32) {
-        let map = self.range_map().unwrap();
+    pub fn find_batch(&self, keys: &[HASH]) -> io::Result<Vec<Result<usize, usize>>> {
+        self.check_positions()?;
+        for key in keys {
+            self.check_covered(key)?;
+        }
+        return Ok(in_key_order(keys, Err(0), |key| self.search(key)));
+    }
 
-        let mut file_index = OpenOptions::new()
-            .create(true)
-            .write(true)
-            .truncate(true)
-            .open(self.dbdir.clone()+"/index.bin")?;
-
-        self.rt.block_on(async {
-            let mut queue = FuturesOrdered::new();
-            let limit = 1000;
-
-            let mut wp = 0u32;
-            let mut rp = 0u32;
-            while rp < 1<<20 {
-                if wp < (1<<20) && queue.len() < limit {
-                    let fut = self.extract_range(&map, wp);
-                    queue.push_back(fut);
-                    wp += 1;
-                } else {
-                    let buff = queue.next().await.unwrap().unwrap();
-                    file_index.write_all(buff.as_slice()).unwrap();
-                    f(rp);
-                    rp += 1;
-                }
+    pub fn lookup_batch(&self, keys: &[HASH]) -> Vec<Lookup> {
+        return in_key_order(keys, Lookup::NotFound, |key| self.lookup(key));
+    }
+
+    // Lookups are memory-mapped reads that do not await, so a large batch hands the worker back
+    // to the caller's runtime every LOOKUP_CHUNK keys.
+    #[cfg(feature = "async")]
+    pub async fn lookup_batch_async(&self, keys: &[HASH]) -> Vec<Lookup> {
+        let mut order: Vec<usize> = (0..keys.len()).collect();
+        order.sort_unstable_by_key(|i| keys[*i]);
+
+        let mut out: Vec<Lookup> = vec![Lookup::NotFound; keys.len()];
+        for chunk in order.chunks(LOOKUP_CHUNK) {
+            for i in chunk {
+                out[*i] = self.lookup(&keys[*i]);
             }
+            tokio::task::yield_now().await;
+        }
+        return out;
+    }
+
+    // Answers keys in arrival order, e.g. hashes read from a socket on the caller's runtime.
+    #[cfg(feature = "async")]
+    pub fn lookup_stream<'s, S>(&'s self, keys: S) -> impl Stream<Item = (HASH, Lookup)> + use<'s, S> where S: Stream<Item = HASH> {
+        return keys.map(move |key| {
+            let v = self.lookup(&key);
+            (key, v)
         });
+    }
 
-        Ok(())
+    pub fn covers(&self, key: &HASH) -> bool {
+        return self.coverage.contains(hash_prefix(key));
     }
 
-    #[inline]
-    pub fn index(&self) -> &[HASH] {
-        let fa: &FileArray<HASH> = self.index.as_ref().unwrap();
-        return fa.as_slice();
+    // A position outside the coverage would only say where the key sorts, not whether it is
+    // pwned.
+    fn check_covered(&self, key: &HASH) -> io::Result<()> {
+        if !self.covers(key) {
+            return Err(io::Error::new(ErrorKind::InvalidInput, format!("{:05X} is outside the index coverage {}", hash_prefix(key), self.coverage)));
+        }
+        Ok(())
     }
 
-    pub fn find(self: &mut Self, key: &HASH) -> Result<usize, usize> {
-        self.index().interpolation_search(key)
+    // Answers membership from the most precise structure available; with only the filter
+    // present a positive answer is wrong with probability `FilterIndex::false_positive_rate`.
+    pub fn lookup(&self, key: &HASH) -> Lookup {
+        if !self.covers(key) {
+            return Lookup::NotCovered;
+        }
+        if self.index.is_none() && self.shards.is_none() && self.compact.is_none() {
+            if let Some(filter) = &self.filter {
+                return if filter.contains(key) { Lookup::Found } else { Lookup::NotFound };
+            }
+        }
+        if self.check_positions().is_err() {
+            return Lookup::NotFound;
+        }
+        match self.search(key).is_ok() {
+            true => Lookup::Found,
+            false => Lookup::NotFound,
+        }
     }
 
     pub fn len(&self) -> usize {
-        self.index.as_ref().unwrap().len()
+        if let Some(fa) = &self.index {
+            return fa.len();
+        }
+        if let Some(shards) = &self.shards {
+            return shards.len();
+        }
+        if let Some(compact) = &self.compact {
+            return compact.len();
+        }
+        if let Some(filter) = &self.filter {
+            return filter.len();
+        }
+        return 0;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
     }
 }
 
@@ -0,0 +1,131 @@
+use std::str::FromStr;
+
+// What to do with a line that is not valid UTF-8 (after `$HEX[]` unwrapping, if enabled).
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum DecodePolicy {
+    #[default]
+    Strict,
+    Latin1,
+    Cp1252,
+    Lossy,
+}
+
+impl FromStr for DecodePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "strict" | "utf8" => Ok(DecodePolicy::Strict),
+            "latin1" | "iso-8859-1" => Ok(DecodePolicy::Latin1),
+            "cp1252" | "windows-1252" => Ok(DecodePolicy::Cp1252),
+            "lossy" => Ok(DecodePolicy::Lossy),
+            _ => Err(format!("unknown decode policy: {}", s)),
+        }
+    }
+}
+
+// `hex` counts lines unwrapped from `$HEX[]`; every accepted line is also counted under the
+// decoding that produced its text.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct DecodeStats {
+    pub utf8: u64,
+    pub hex: u64,
+    pub latin1: u64,
+    pub cp1252: u64,
+    pub lossy: u64,
+    pub rejected: u64,
+}
+
+// 0x80..=0x9F in Windows-1252; the five unassigned bytes map to the C1 controls as in WHATWG.
+const CP1252_HIGH: [char; 32] = [
+    '\u{20AC}', '\u{0081}', '\u{201A}', '\u{0192}', '\u{201E}', '\u{2026}', '\u{2020}', '\u{2021}',
+    '\u{02C6}', '\u{2030}', '\u{0160}', '\u{2039}', '\u{0152}', '\u{008D}', '\u{017D}', '\u{008F}',
+    '\u{0090}', '\u{2018}', '\u{2019}', '\u{201C}', '\u{201D}', '\u{2022}', '\u{2013}', '\u{2014}',
+    '\u{02DC}', '\u{2122}', '\u{0161}', '\u{203A}', '\u{0153}', '\u{009D}', '\u{017E}', '\u{0178}',
+];
+
+fn cp1252_char(v: u8) -> char {
+    match v {
+        0x80..=0x9F => CP1252_HIGH[(v - 0x80) as usize],
+        _ => v as char,
+    }
+}
+
+// hashcat writes passwords containing separators or non-printable bytes as `$HEX[<hex>]`.
+pub fn unhex(line: &[u8], out: &mut Vec<u8>) -> bool {
+    let inner = match line.strip_prefix(b"$HEX[").and_then(|v| v.strip_suffix(b"]")) {
+        Some(v) if v.len() % 2 == 0 => v,
+        _ => return false,
+    };
+
+    let start = out.len();
+    out.resize(start + inner.len()/2, 0);
+    if hex::decode_to_slice(inner, &mut out[start..]).is_err() {
+        out.truncate(start);
+        return false;
+    }
+    return true;
+}
+
+pub struct PasswordDecoder {
+    pub policy: DecodePolicy,
+    pub hex: bool,
+    pub stats: DecodeStats,
+    scratch: Vec<u8>,
+}
+
+impl PasswordDecoder {
+    pub fn new(policy: DecodePolicy, hex: bool) -> Self {
+        Self {
+            policy,
+            hex,
+            stats: DecodeStats::default(),
+            scratch: Vec::new(),
+        }
+    }
+
+    // Replaces the contents of `out` with the UTF-8 text of `line`; returns false, leaving `out`
+    // empty, when the policy rejects the line.
+    pub fn decode(&mut self, line: &[u8], out: &mut Vec<u8>) -> bool {
+        out.clear();
+
+        self.scratch.clear();
+        let raw = if self.hex && unhex(line, &mut self.scratch) {
+            self.stats.hex += 1;
+            self.scratch.as_slice()
+        } else {
+            line
+        };
+
+        if std::str::from_utf8(raw).is_ok() {
+            out.extend_from_slice(raw);
+            self.stats.utf8 += 1;
+            return true;
+        }
+
+        let mut buff = [0u8; 4];
+        match self.policy {
+            DecodePolicy::Strict => {
+                self.stats.rejected += 1;
+                return false;
+            }
+            DecodePolicy::Latin1 => {
+                for v in raw {
+                    out.extend_from_slice((*v as char).encode_utf8(&mut buff).as_bytes());
+                }
+                self.stats.latin1 += 1;
+            }
+            DecodePolicy::Cp1252 => {
+                for v in raw {
+                    out.extend_from_slice(cp1252_char(*v).encode_utf8(&mut buff).as_bytes());
+                }
+                self.stats.cp1252 += 1;
+            }
+            DecodePolicy::Lossy => {
+                out.extend_from_slice(String::from_utf8_lossy(raw).as_bytes());
+                self.stats.lossy += 1;
+            }
+        }
+        return true;
+    }
+}
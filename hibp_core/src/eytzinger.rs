@@ -0,0 +1,120 @@
+use std::io;
+use std::io::ErrorKind;
+
+use crate::db::{FileArray, HIBPDB};
+use crate::HASH;
+
+pub const EYTZINGER_INDEX: &str = "index.eytzinger";
+
+// The layout is 1-based: slot 0 is padding, the root lives in slot 1 and the children of slot
+// k are 2k and 2k+1. With 16 byte records a 64 byte cache line holds the four grandchildren
+// 4k..4k+3, which is what the search prefetches while it compares against slot k.
+pub trait EytzingerSearch<T> {
+    fn eytzinger_search(&self, key: &T) -> Result<usize, usize>;
+}
+
+#[inline(always)]
+fn prefetch<T>(ptr: *const T) {
+    #[cfg(target_arch = "x86_64")]
+    unsafe {
+        std::arch::x86_64::_mm_prefetch::<{ std::arch::x86_64::_MM_HINT_T0 }>(ptr as *const i8);
+    }
+    #[cfg(not(target_arch = "x86_64"))]
+    let _ = ptr;
+}
+
+impl EytzingerSearch<HASH> for [HASH] {
+    // Ok(k) is the slot holding `key`, Err(k) the slot of the smallest element greater than
+    // `key`, where 0 means there is none.
+    #[inline]
+    fn eytzinger_search(&self, key: &HASH) -> Result<usize, usize> {
+        let n = self.len();
+        let mut k = 1usize;
+        while k < n {
+            prefetch(self.as_ptr().wrapping_add(4*k));
+            k = 2*k + (self[k] < *key) as usize;
+        }
+        k >>= k.trailing_ones() + 1;
+
+        if k != 0 && self[k] == *key {
+            Ok(k)
+        } else {
+            Err(k)
+        }
+    }
+}
+
+// Position in the sorted array of the element in `slot` of a layout of `n` elements, with
+// slot 0 (no greater element) mapping to `n`. The layout is a complete tree whose last level
+// fills from the left, so this is the slot's in-order rank in the full tree of that height
+// minus the missing leaves that would come before it.
+#[inline]
+pub fn eytzinger_rank(slot: usize, n: usize) -> usize {
+    if slot == 0 {
+        return n;
+    }
+    let levels = (usize::BITS - n.leading_zeros()) as usize;
+    let depth = slot.ilog2() as usize;
+    let full = ((2*(slot - (1 << depth)) + 1) << (levels - 1 - depth)) - 1;
+
+    let leaves = 1usize << (levels - 1);
+    let present = n + 1 - leaves;
+    let missing = usize::min(full.div_ceil(2), leaves).saturating_sub(present);
+    return full - missing;
+}
+
+// Writes `sorted` into `out` (which must be one element longer) in Eytzinger order. Slots are
+// visited in order so `sorted` is read sequentially, which matters when both are mmaps.
+pub fn eytzinger_layout(sorted: &[HASH], out: &mut [HASH]) {
+    assert_eq!(sorted.len()+1, out.len());
+    out[0] = [0u8; 16];
+
+    let n = out.len();
+    let mut i = 0usize;
+    let mut stack: Vec<usize> = Vec::with_capacity(64);
+    let mut k = 1usize;
+    loop {
+        while k < n {
+            stack.push(k);
+            k *= 2;
+        }
+        match stack.pop() {
+            Some(v) => {
+                out[v] = sorted[i];
+                i += 1;
+                k = 2*v + 1;
+            }
+            None => break,
+        }
+    }
+}
+
+impl HIBPDB {
+
+    pub fn construct_eytzinger(&mut self) -> io::Result<()> {
+        let sorted: &[HASH] = match &self.index {
+            Some(v) => v.as_slice(),
+            None => return Err(io::Error::new(ErrorKind::NotFound, "index.bin not found")),
+        };
+
+        let pathname = self.dbdir.clone()+"/"+EYTZINGER_INDEX;
+        let out = FileArray::create(pathname, sorted.len()+1, &self.options, |out| eytzinger_layout(sorted, out))?;
+        self.eytzinger = Some(out);
+        Ok(())
+    }
+
+    #[inline]
+    pub fn eytzinger(&self) -> &[HASH] {
+        return self.eytzinger.as_ref().unwrap().as_slice();
+    }
+
+    // Eytzinger search with the result mapped back to positions in index.bin.
+    #[inline]
+    pub(crate) fn eytzinger_find(&self, key: &HASH) -> Result<usize, usize> {
+        let layout = self.eytzinger();
+        let n = layout.len() - 1;
+        return layout.eytzinger_search(key)
+            .map(|v| eytzinger_rank(v, n))
+            .map_err(|v| eytzinger_rank(v, n));
+    }
+}
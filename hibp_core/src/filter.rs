@@ -0,0 +1,460 @@
+// Binary fuse filters, after "Binary Fuse Filters: Fast and Smaller Than Xor Filters"
+// (Graf & Lemire, 2022) and the reference xor_singleheader implementation.
+//
+// The hash space is split into 2^bucket_bits buckets by prefix and every bucket gets its own
+// filter, so the filter can be built while `construct_index` streams ranges in order without
+// ever holding more than one bucket's keys in memory.
+
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{BufWriter, ErrorKind, Seek, SeekFrom, Write};
+
+use memmap2::Mmap;
+
+use crate::db::IndexSink;
+use crate::residency::IndexOptions;
+use crate::verify::hash_prefix;
+use crate::HASH;
+
+pub const FILTER_INDEX: &str = "index.filter";
+pub const DEFAULT_BUCKET_BITS: u32 = 8;
+
+const MAGIC: &[u8; 8] = b"HIBPFUSE";
+const VERSION: u32 = 1;
+const HEADER_SIZE: usize = 40;
+const BUCKET_SIZE: usize = 32;
+const ARITY: u32 = 3;
+const MAX_ITERATIONS: usize = 100;
+const MAX_EXTRA_SEGMENTS: u32 = 4;
+
+#[inline]
+fn murmur64(mut h: u64) -> u64 {
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xff51afd7ed558ccd);
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xc4ceb9fe1a85ec53);
+    h ^= h >> 33;
+    return h;
+}
+
+#[inline]
+fn splitmix64(seed: &mut u64) -> u64 {
+    *seed = seed.wrapping_add(0x9e3779b97f4a7c15);
+    let mut z = *seed;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    return z ^ (z >> 31);
+}
+
+#[inline]
+fn fingerprint(hash: u64) -> u8 {
+    return (hash ^ (hash >> 32)) as u8;
+}
+
+#[inline]
+pub fn filter_key(hash: &HASH) -> u64 {
+    let hi = u64::from_be_bytes(hash[0..8].try_into().unwrap());
+    let lo = u64::from_be_bytes(hash[8..16].try_into().unwrap());
+    return hi ^ lo;
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct BinaryFuse8 {
+    pub seed: u64,
+    pub segment_length: u32,
+    pub segment_length_mask: u32,
+    pub segment_count_length: u32,
+    pub array_length: u32,
+}
+
+impl BinaryFuse8 {
+
+    fn allocate(size: u32, extra_segments: u32) -> Self {
+        let mut segment_length = match size {
+            0 => 4,
+            _ => 1u32 << ((size as f64).ln() / 3.33f64.ln() + 2.25).floor() as i32,
+        };
+        if segment_length > 262144 {
+            segment_length = 262144;
+        }
+        let size_factor = match size {
+            0 | 1 => 0.0,
+            _ => f64::max(1.125, 0.875 + 0.25 * 1000000f64.ln() / (size as f64).ln()),
+        };
+        let capacity = (size as f64 * size_factor).round() as u32;
+
+        // Mirrors the reference implementation, including its unsigned wrap-around for tiny sets.
+        let init_segment_count = (capacity.wrapping_add(segment_length) - 1) / segment_length;
+        let init_segment_count = init_segment_count.wrapping_sub(ARITY - 1);
+        let array_length = init_segment_count.wrapping_add(ARITY - 1).wrapping_mul(segment_length);
+        let mut segment_count = (array_length.wrapping_add(segment_length) - 1) / segment_length;
+        if segment_count < ARITY {
+            segment_count = 1;
+        } else {
+            segment_count -= ARITY - 1;
+        }
+        segment_count += extra_segments;
+
+        Self {
+            seed: 0,
+            segment_length,
+            segment_length_mask: segment_length - 1,
+            segment_count_length: segment_count * segment_length,
+            array_length: (segment_count + ARITY - 1) * segment_length,
+        }
+    }
+
+    #[inline]
+    fn hash_batch(&self, hash: u64) -> [u32; 3] {
+        let hi = ((hash as u128 * self.segment_count_length as u128) >> 64) as u32;
+        let h0 = hi;
+        let mut h1 = h0 + self.segment_length;
+        let mut h2 = h1 + self.segment_length;
+        h1 ^= (hash >> 18) as u32 & self.segment_length_mask;
+        h2 ^= hash as u32 & self.segment_length_mask;
+        return [h0, h1, h2];
+    }
+
+    // Sizes that leave almost no slack after rounding up to whole segments can fail to peel
+    // for nearly every seed, so each failed attempt widens the array by one more segment.
+    pub fn build(keys: &[u64]) -> Result<(Self, Vec<u8>), &'static str> {
+        for extra_segments in 0..MAX_EXTRA_SEGMENTS {
+            if let Some(v) = Self::build_with(keys, extra_segments) {
+                return Ok(v);
+            }
+        }
+        return Err("failed to construct binary fuse filter");
+    }
+
+    fn build_with(keys: &[u64], extra_segments: u32) -> Option<(Self, Vec<u8>)> {
+        let size = keys.len();
+        let mut filter = Self::allocate(size as u32, extra_segments);
+        let array_length = filter.array_length as usize;
+
+        let mut rng_counter = 0x726b2b9d438b9d4du64;
+        filter.seed = splitmix64(&mut rng_counter);
+
+        let mut reverse_order = vec![0u64; size+1];
+        let mut reverse_h = vec![0u8; size];
+        let mut alone = vec![0u32; array_length];
+        let mut t2count = vec![0u8; array_length];
+        let mut t2hash = vec![0u64; array_length];
+
+        let mut block_bits = 1u32;
+        while (1usize << block_bits) < (filter.segment_count_length / filter.segment_length) as usize {
+            block_bits += 1;
+        }
+        let block = 1usize << block_bits;
+        let mut start_pos = vec![0usize; block];
+
+        reverse_order[size] = 1;
+        let mut duplicates: usize;
+        let mut stack_size: usize;
+        let mut iteration = 0usize;
+        loop {
+            iteration += 1;
+            if iteration > MAX_ITERATIONS {
+                return None;
+            }
+
+            for (i, v) in start_pos.iter_mut().enumerate() {
+                *v = (i * size) >> block_bits;
+            }
+            let mask_block = block - 1;
+            for key in keys {
+                let hash = murmur64(key.wrapping_add(filter.seed));
+                let mut segment_index = (hash >> (64 - block_bits)) as usize;
+                while reverse_order[start_pos[segment_index]] != 0 {
+                    segment_index = (segment_index + 1) & mask_block;
+                }
+                reverse_order[start_pos[segment_index]] = hash;
+                start_pos[segment_index] += 1;
+            }
+
+            let mut error = false;
+            duplicates = 0;
+            for &hash in &reverse_order[0..size] {
+                let [h0, h1, h2] = filter.hash_batch(hash).map(|v| v as usize);
+                t2count[h0] = t2count[h0].wrapping_add(4);
+                t2hash[h0] ^= hash;
+                t2count[h1] = t2count[h1].wrapping_add(4);
+                t2count[h1] ^= 1;
+                t2hash[h1] ^= hash;
+                t2count[h2] = t2count[h2].wrapping_add(4);
+                t2count[h2] ^= 2;
+                t2hash[h2] ^= hash;
+
+                if t2hash[h0] & t2hash[h1] & t2hash[h2] == 0
+                    && ((t2hash[h0] == 0 && t2count[h0] == 8)
+                    || (t2hash[h1] == 0 && t2count[h1] == 8)
+                    || (t2hash[h2] == 0 && t2count[h2] == 8)) {
+                    duplicates += 1;
+                    t2count[h0] = t2count[h0].wrapping_sub(4);
+                    t2hash[h0] ^= hash;
+                    t2count[h1] = t2count[h1].wrapping_sub(4);
+                    t2count[h1] ^= 1;
+                    t2hash[h1] ^= hash;
+                    t2count[h2] = t2count[h2].wrapping_sub(4);
+                    t2count[h2] ^= 2;
+                    t2hash[h2] ^= hash;
+                }
+                error |= t2count[h0] < 4 || t2count[h1] < 4 || t2count[h2] < 4;
+            }
+
+            if !error {
+                let mut qsize = 0usize;
+                for (i, count) in t2count.iter().enumerate() {
+                    alone[qsize] = i as u32;
+                    if count >> 2 == 1 {
+                        qsize += 1;
+                    }
+                }
+
+                stack_size = 0;
+                while qsize > 0 {
+                    qsize -= 1;
+                    let index = alone[qsize] as usize;
+                    if t2count[index] >> 2 != 1 {
+                        continue;
+                    }
+                    let hash = t2hash[index];
+                    let found = t2count[index] & 3;
+                    reverse_h[stack_size] = found;
+                    reverse_order[stack_size] = hash;
+                    stack_size += 1;
+
+                    let [h0, h1, h2] = filter.hash_batch(hash);
+                    let h012 = [h0, h1, h2, h0, h1];
+                    for k in 1..3u8 {
+                        let other = h012[(found + k) as usize] as usize;
+                        alone[qsize] = other as u32;
+                        if t2count[other] >> 2 == 2 {
+                            qsize += 1;
+                        }
+                        t2count[other] = t2count[other].wrapping_sub(4);
+                        t2count[other] ^= (found + k) % 3;
+                        t2hash[other] ^= hash;
+                    }
+                }
+
+                if stack_size + duplicates == size {
+                    break;
+                }
+            }
+
+            reverse_order[0..size].fill(0);
+            t2count.fill(0);
+            t2hash.fill(0);
+            filter.seed = splitmix64(&mut rng_counter);
+        }
+
+        let mut fingerprints = vec![0u8; array_length];
+        for i in (0..stack_size).rev() {
+            let hash = reverse_order[i];
+            let [h0, h1, h2] = filter.hash_batch(hash);
+            let h012 = [h0, h1, h2, h0, h1].map(|v| v as usize);
+            let found = reverse_h[i] as usize;
+            fingerprints[h012[found]] = fingerprint(hash) ^ fingerprints[h012[found+1]] ^ fingerprints[h012[found+2]];
+        }
+
+        Some((filter, fingerprints))
+    }
+
+    #[inline]
+    pub fn contains(&self, fingerprints: &[u8], key: u64) -> bool {
+        let hash = murmur64(key.wrapping_add(self.seed));
+        let [h0, h1, h2] = self.hash_batch(hash);
+        let f = fingerprint(hash) ^ fingerprints[h0 as usize] ^ fingerprints[h1 as usize] ^ fingerprints[h2 as usize];
+        return f == 0;
+    }
+
+    pub fn false_positive_rate() -> f64 {
+        return 1.0 / 256.0;
+    }
+}
+
+// FNV-1a over the names of the range files the filter was built from. The names embed each
+// range's etag, so a refreshed corpus yields a different snapshot id.
+pub fn snapshot_id(range_map: &[String]) -> u64 {
+    let mut h = 0xcbf29ce484222325u64;
+    for name in range_map {
+        for b in name.as_bytes().iter().chain(b"\n") {
+            h ^= *b as u64;
+            h = h.wrapping_mul(0x100000001b3);
+        }
+    }
+    return h;
+}
+
+pub struct FilterWriter {
+    out: BufWriter<File>,
+    bucket_bits: u32,
+    snapshot: u64,
+    buckets: Vec<BinaryFuse8>,
+    offsets: Vec<u64>,
+    pending: Vec<u64>,
+    records: u64,
+    written: u64,
+}
+
+impl FilterWriter {
+    pub fn create(pathname: &str, bucket_bits: u32, snapshot: u64) -> io::Result<Self> {
+        if bucket_bits > 20 {
+            return Err(io::Error::new(ErrorKind::InvalidInput, "bucket_bits must be at most 20"));
+        }
+
+        let fd = OpenOptions::new().create(true).write(true).truncate(true).open(pathname)?;
+        let mut out = BufWriter::new(fd);
+        out.write_all(&vec![0u8; HEADER_SIZE + BUCKET_SIZE*(1 << bucket_bits)])?;
+
+        Ok(Self {
+            out,
+            bucket_bits,
+            snapshot,
+            buckets: Vec::with_capacity(1 << bucket_bits),
+            offsets: Vec::with_capacity(1 << bucket_bits),
+            pending: Vec::new(),
+            records: 0,
+            written: 0,
+        })
+    }
+
+    fn flush_bucket(&mut self) -> io::Result<()> {
+        let (filter, fingerprints) = BinaryFuse8::build(self.pending.as_slice())
+            .map_err(io::Error::other)?;
+        self.out.write_all(fingerprints.as_slice())?;
+        self.buckets.push(filter);
+        self.offsets.push(self.written);
+        self.written += fingerprints.len() as u64;
+        self.pending.clear();
+        Ok(())
+    }
+
+    pub fn push_range(&mut self, range: u32, hashes: &[u8]) -> io::Result<()> {
+        let bucket = (range >> (20 - self.bucket_bits)) as usize;
+        while self.buckets.len() < bucket {
+            self.flush_bucket()?;
+        }
+        for hash in hashes.chunks_exact(16) {
+            self.pending.push(filter_key(hash.try_into().unwrap()));
+            self.records += 1;
+        }
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> io::Result<()> {
+        while self.buckets.len() < (1 << self.bucket_bits) {
+            self.flush_bucket()?;
+        }
+
+        let mut header: Vec<u8> = Vec::with_capacity(HEADER_SIZE + BUCKET_SIZE*self.buckets.len());
+        header.extend(MAGIC);
+        header.extend(VERSION.to_le_bytes());
+        header.extend(self.bucket_bits.to_le_bytes());
+        header.extend(self.records.to_le_bytes());
+        header.extend(BinaryFuse8::false_positive_rate().to_le_bytes());
+        header.extend(self.snapshot.to_le_bytes());
+        for (filter, offset) in self.buckets.iter().zip(&self.offsets) {
+            header.extend(filter.seed.to_le_bytes());
+            header.extend(filter.segment_length.to_le_bytes());
+            header.extend(filter.segment_count_length.to_le_bytes());
+            header.extend(filter.array_length.to_le_bytes());
+            header.extend(0u32.to_le_bytes());
+            header.extend(offset.to_le_bytes());
+        }
+
+        self.out.seek(SeekFrom::Start(0))?;
+        self.out.write_all(header.as_slice())?;
+        self.out.flush()?;
+        Ok(())
+    }
+}
+
+impl IndexSink for FilterWriter {
+    fn push_range(&mut self, range: u32, hashes: &[u8]) -> io::Result<()> {
+        FilterWriter::push_range(self, range, hashes)
+    }
+
+    fn finish(self: Box<Self>) -> io::Result<()> {
+        FilterWriter::finish(*self)
+    }
+}
+
+pub struct FilterIndex {
+    pub mmap: Mmap,
+    pub bucket_bits: u32,
+    pub records: u64,
+    pub false_positive_rate: f64,
+    pub snapshot: u64,
+    pub buckets: Vec<(BinaryFuse8, usize)>,
+}
+
+impl FilterIndex {
+    pub fn open(pathname: &str, options: &IndexOptions) -> io::Result<Self> {
+        let fd = File::open(pathname)?;
+        let mmap = options.map(&fd)?;
+
+        let invalid = |msg: &str| io::Error::new(ErrorKind::InvalidData, String::from(msg));
+        if mmap.len() < HEADER_SIZE || &mmap[0..8] != MAGIC {
+            return Err(invalid("not a filter index"));
+        }
+        let u32_at = |off: usize| u32::from_le_bytes(mmap[off..off+4].try_into().unwrap());
+        let u64_at = |off: usize| u64::from_le_bytes(mmap[off..off+8].try_into().unwrap());
+        if u32_at(8) != VERSION {
+            return Err(invalid("unsupported filter index version"));
+        }
+
+        let bucket_bits = u32_at(12);
+        if bucket_bits > 20 {
+            return Err(invalid("filter index bucket_bits is outside 0..=20"));
+        }
+        let data = HEADER_SIZE + BUCKET_SIZE*(1 << bucket_bits);
+        if mmap.len() < data {
+            return Err(invalid("filter index is truncated"));
+        }
+
+        let mut buckets: Vec<(BinaryFuse8, usize)> = Vec::with_capacity(1 << bucket_bits);
+        for i in 0..(1usize << bucket_bits) {
+            let off = HEADER_SIZE + BUCKET_SIZE*i;
+            let segment_length = u32_at(off+8);
+            let filter = BinaryFuse8 {
+                seed: u64_at(off),
+                segment_length,
+                segment_length_mask: segment_length.wrapping_sub(1),
+                segment_count_length: u32_at(off+12),
+                array_length: u32_at(off+16),
+            };
+            let start = (u64_at(off+24) as usize).checked_add(data);
+            match start.and_then(|v| v.checked_add(filter.array_length as usize)) {
+                Some(end) if end <= mmap.len() => {},
+                _ => return Err(invalid("filter index is truncated")),
+            }
+            buckets.push((filter, start.unwrap()));
+        }
+
+        Ok(Self {
+            bucket_bits,
+            records: u64_at(16),
+            false_positive_rate: f64::from_le_bytes(mmap[24..32].try_into().unwrap()),
+            snapshot: u64_at(32),
+            buckets,
+            mmap,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        return self.records as usize;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.records == 0;
+    }
+
+    #[inline]
+    pub fn contains(&self, key: &HASH) -> bool {
+        let bucket = (hash_prefix(key) >> (20 - self.bucket_bits)) as usize;
+        let (filter, start) = &self.buckets[bucket];
+        let fingerprints = &self.mmap[*start..*start + filter.array_length as usize];
+        return filter.contains(fingerprints, filter_key(key));
+    }
+}
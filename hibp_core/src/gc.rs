@@ -0,0 +1,77 @@
+use std::fs;
+use std::io;
+use std::time::SystemTime;
+
+use crate::db::HIBPDB;
+use crate::dir_list;
+
+#[derive(Debug, Default, Clone)]
+pub struct GcOptions {
+    pub dry_run: bool,
+    pub archive: bool,
+}
+
+#[derive(Debug, Default)]
+pub struct GcReport {
+    pub kept: u64,
+    pub superseded: Vec<String>,
+    pub temp: Vec<String>,
+}
+
+impl HIBPDB {
+
+    pub(crate) fn range_file_version(&self, filename: &str) -> (SystemTime, u64) {
+        let pathname = self.dbdir.clone()+"/range/"+filename;
+        let modified = fs::metadata(pathname)
+            .and_then(|v| v.modified())
+            .unwrap_or(SystemTime::UNIX_EPOCH);
+        let etag = u64::from_str_radix(&filename[6..22], 16).unwrap_or(0);
+        return (modified, etag);
+    }
+
+    // Keeps the newest file of every range, ordered by the last-modified time `save` stamps on
+    // it and then by etag, and gets rid of the older versions and of leftover `tmp.` files.
+    pub fn gc(&self, opts: &GcOptions) -> io::Result<GcReport> {
+        let dir_range = self.dbdir.clone()+"/range/";
+        let dir_archive = self.dbdir.clone()+"/archive/";
+        let mut report = GcReport::default();
+
+        for filename in dir_list(dir_range.as_str())? {
+            if filename.starts_with("tmp.") {
+                report.temp.push(filename);
+            }
+        }
+        report.temp.sort();
+
+        for mut files in self.range_files()? {
+            if files.is_empty() {
+                continue;
+            }
+            files.sort_by_cached_key(|v| self.range_file_version(v.as_str()));
+            files.pop();
+            report.kept += 1;
+            report.superseded.extend(files);
+        }
+
+        if opts.dry_run {
+            return Ok(report);
+        }
+
+        for filename in &report.temp {
+            fs::remove_file(dir_range.clone()+filename.as_str())?;
+        }
+
+        if opts.archive && !report.superseded.is_empty() {
+            fs::create_dir_all(dir_archive.clone())?;
+        }
+        for filename in &report.superseded {
+            if opts.archive {
+                fs::rename(dir_range.clone()+filename.as_str(), dir_archive.clone()+filename.as_str())?;
+            } else {
+                fs::remove_file(dir_range.clone()+filename.as_str())?;
+            }
+        }
+
+        return Ok(report);
+    }
+}
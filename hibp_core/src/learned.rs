@@ -0,0 +1,206 @@
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{BufWriter, ErrorKind, Write};
+
+use memmap2::Mmap;
+
+use crate::db::IndexSink;
+use crate::residency::IndexOptions;
+use crate::verify::{hash_prefix, RANGE_COUNT};
+use crate::HASH;
+
+pub const LEARNED_MODEL: &str = "index.model";
+
+const MAGIC: &[u8; 8] = b"HIBPLRNM";
+const VERSION: u32 = 1;
+const HEADER_SIZE: usize = 24;
+const ENTRY_SIZE: usize = 32;
+
+// The 44 bits that follow the 20-bit prefix; within a fan-out bucket these are what the
+// position is predicted from.
+#[inline]
+fn model_input(key: &HASH) -> f64 {
+    let v = u64::from_be_bytes(key[0..8].try_into().unwrap());
+    return (v & ((1u64 << 44) - 1)) as f64;
+}
+
+// One linear segment per fan-out bucket: position = base + intercept + slope*x, off by at most
+// `max_error` slots for every key stored in the bucket.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct BucketModel {
+    pub base: u64,
+    pub slope: f64,
+    pub intercept: f64,
+    pub max_error: u32,
+    pub count: u32,
+}
+
+impl BucketModel {
+    pub fn fit(base: u64, keys: &[HASH]) -> Self {
+        let n = keys.len();
+        let mut model = BucketModel {
+            base,
+            count: n as u32,
+            ..Default::default()
+        };
+        if n == 0 {
+            return model;
+        }
+
+        let mean_x = keys.iter().map(model_input).sum::<f64>() / n as f64;
+        let mean_y = (n as f64 - 1.0) / 2.0;
+        let mut sxy = 0f64;
+        let mut sxx = 0f64;
+        for (i, key) in keys.iter().enumerate() {
+            let dx = model_input(key) - mean_x;
+            sxy += dx * (i as f64 - mean_y);
+            sxx += dx * dx;
+        }
+        model.slope = if sxx > 0.0 { sxy / sxx } else { 0.0 };
+        model.intercept = mean_y - model.slope*mean_x;
+
+        let mut max_error = 0f64;
+        for (i, key) in keys.iter().enumerate() {
+            max_error = f64::max(max_error, (model.predict(key) as f64 - i as f64).abs());
+        }
+        model.max_error = max_error.ceil() as u32;
+        return model;
+    }
+
+    #[inline]
+    pub fn predict(&self, key: &HASH) -> usize {
+        let p = self.intercept + self.slope*model_input(key);
+        return p.round().clamp(0.0, self.count.saturating_sub(1) as f64) as usize;
+    }
+
+    fn encode(&self) -> [u8; ENTRY_SIZE] {
+        let mut raw = [0u8; ENTRY_SIZE];
+        raw[0..8].copy_from_slice(&self.base.to_le_bytes());
+        raw[8..16].copy_from_slice(&self.slope.to_le_bytes());
+        raw[16..24].copy_from_slice(&self.intercept.to_le_bytes());
+        raw[24..28].copy_from_slice(&self.max_error.to_le_bytes());
+        raw[28..32].copy_from_slice(&self.count.to_le_bytes());
+        return raw;
+    }
+
+    fn decode(raw: &[u8]) -> Self {
+        Self {
+            base: u64::from_le_bytes(raw[0..8].try_into().unwrap()),
+            slope: f64::from_le_bytes(raw[8..16].try_into().unwrap()),
+            intercept: f64::from_le_bytes(raw[16..24].try_into().unwrap()),
+            max_error: u32::from_le_bytes(raw[24..28].try_into().unwrap()),
+            count: u32::from_le_bytes(raw[28..32].try_into().unwrap()),
+        }
+    }
+}
+
+pub struct LearnedModelWriter {
+    out: BufWriter<File>,
+    next: u32,
+    records: u64,
+}
+
+impl LearnedModelWriter {
+    pub fn create(pathname: &str) -> io::Result<Self> {
+        let fd = OpenOptions::new().create(true).write(true).truncate(true).open(pathname)?;
+        let mut out = BufWriter::new(fd);
+        out.write_all(MAGIC)?;
+        out.write_all(&VERSION.to_le_bytes())?;
+        out.write_all(&0u32.to_le_bytes())?;
+        out.write_all(&0u64.to_le_bytes())?;
+
+        Ok(Self {
+            out,
+            next: 0,
+            records: 0,
+        })
+    }
+
+    pub fn push_range(&mut self, range: u32, hashes: &[u8]) -> io::Result<()> {
+        while self.next < range {
+            self.out.write_all(&BucketModel { base: self.records, ..Default::default() }.encode())?;
+            self.next += 1;
+        }
+
+        let keys: Vec<HASH> = hashes.chunks_exact(16).map(|v| v.try_into().unwrap()).collect();
+        self.out.write_all(&BucketModel::fit(self.records, keys.as_slice()).encode())?;
+        self.records += keys.len() as u64;
+        self.next += 1;
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> io::Result<()> {
+        while (self.next as usize) < RANGE_COUNT {
+            self.out.write_all(&BucketModel { base: self.records, ..Default::default() }.encode())?;
+            self.next += 1;
+        }
+        self.out.flush()?;
+
+        let fd = self.out.get_mut();
+        std::os::unix::fs::FileExt::write_all_at(fd, &self.records.to_le_bytes(), 16)?;
+        Ok(())
+    }
+}
+
+impl IndexSink for LearnedModelWriter {
+    fn push_range(&mut self, range: u32, hashes: &[u8]) -> io::Result<()> {
+        LearnedModelWriter::push_range(self, range, hashes)
+    }
+
+    fn finish(self: Box<Self>) -> io::Result<()> {
+        LearnedModelWriter::finish(*self)
+    }
+}
+
+pub struct LearnedIndex {
+    pub mmap: Mmap,
+    pub records: u64,
+}
+
+impl LearnedIndex {
+    pub fn open(pathname: &str, options: &IndexOptions) -> io::Result<Self> {
+        let fd = File::open(pathname)?;
+        let mmap = options.map(&fd)?;
+
+        if mmap.len() != HEADER_SIZE + RANGE_COUNT*ENTRY_SIZE || &mmap[0..8] != MAGIC {
+            return Err(io::Error::new(ErrorKind::InvalidData, "not a learned index model"));
+        }
+        if u32::from_le_bytes(mmap[8..12].try_into().unwrap()) != VERSION {
+            return Err(io::Error::new(ErrorKind::InvalidData, "unsupported learned index model version"));
+        }
+
+        Ok(Self {
+            records: u64::from_le_bytes(mmap[16..24].try_into().unwrap()),
+            mmap,
+        })
+    }
+
+    #[inline]
+    pub fn model(&self, range: u32) -> BucketModel {
+        let off = HEADER_SIZE + ENTRY_SIZE*range as usize;
+        return BucketModel::decode(&self.mmap[off..off+ENTRY_SIZE]);
+    }
+
+    // Binary search within the model's error window around the prediction; the window is one
+    // slot wider than the error bound so insertion points of absent keys are covered as well.
+    #[inline]
+    pub fn search(&self, index: &[HASH], key: &HASH) -> Result<usize, usize> {
+        let model = self.model(hash_prefix(key));
+        let base = model.base as usize;
+        let count = model.count as usize;
+
+        let predicted = model.predict(key);
+        let err = model.max_error as usize + 1;
+        let lo = predicted.saturating_sub(err);
+        let hi = usize::min(predicted + err + 1, count);
+
+        let bucket = &index[base..base+count];
+        match bucket[lo..hi].binary_search(key) {
+            Ok(v) => Ok(base+lo+v),
+            Err(v) if (v == 0 && lo > 0) || (v == hi-lo && hi < count) => {
+                bucket.binary_search(key).map(|v| base+v).map_err(|v| base+v)
+            }
+            Err(v) => Err(base+lo+v),
+        }
+    }
+}
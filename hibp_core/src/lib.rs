@@ -1,18 +1,52 @@
-pub mod db;
+#![allow(clippy::needless_return)]
 
+pub mod archive;
+pub mod compact;
+#[cfg(feature = "build")]
+pub mod construct;
+pub mod coverage;
+pub mod db;
+pub mod decode;
+pub mod eytzinger;
+pub mod filter;
+#[cfg(feature = "codecs")]
+pub mod gc;
+pub mod learned;
+pub mod md4_fast;
+pub mod progress;
+pub mod residency;
+pub mod reader;
+pub mod rules;
+pub mod shard;
+#[cfg(feature = "codecs")]
+pub mod store;
+#[cfg(feature = "download")]
+pub mod throttle;
+#[cfg(feature = "download")]
+pub mod update;
+#[cfg(feature = "codecs")]
+pub mod validate;
+pub mod verify;
+
+#[cfg(feature = "download")]
 use std::fmt::{Debug, Formatter};
 use std::mem::{size_of};
 use std::{slice};
+#[cfg(feature = "codecs")]
 use std::io::{Read, Write};
 use std::panic::UnwindSafe;
-use std::str::Utf8Error;
+use std::str::{FromStr, Utf8Error};
+#[cfg(feature = "download")]
 use chrono::DateTime;
+#[cfg(feature = "codecs")]
 use flate2::Compression;
+#[cfg(feature = "codecs")]
 use flate2::write::GzEncoder;
+#[cfg(feature = "codecs")]
 use xz2::read::XzDecoder;
 
-use md4::{Digest, Md4};
 use rand::{RngCore, SeedableRng};
+#[cfg(feature = "codecs")]
 use xz2::write::XzEncoder;
 
 pub type HASH = [u8; 16];
@@ -23,16 +57,19 @@ pub fn HASH_to_hex(v: &HASH) -> String {
 }
 
 
+#[cfg(feature = "download")]
 pub struct DownloadError {
     range: u32,
 }
 
+#[cfg(feature = "download")]
 impl Debug for DownloadError {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         f.write_str(format!("{:05X}", self.range).as_str())
     }
 }
 
+#[cfg(feature = "codecs")]
 pub fn extract_gz(compressed: &[u8]) -> std::io::Result<Vec<u8>> {
     let mut decoder = flate2::read::GzDecoder::new(compressed);
     let mut plain = Vec::new();
@@ -40,12 +77,14 @@ pub fn extract_gz(compressed: &[u8]) -> std::io::Result<Vec<u8>> {
     return Ok(plain);
 }
 
+#[cfg(feature = "codecs")]
 pub fn compress_gz(plain: &[u8]) -> std::io::Result<Vec<u8>> {
     let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
     encoder.write_all(plain)?;
     encoder.finish()
 }
 
+#[cfg(feature = "codecs")]
 pub fn extract_xz(compressed: &[u8]) -> std::io::Result<Vec<u8>> {
     let mut decoder = XzDecoder::new(compressed);
     let mut plain = Vec::new();
@@ -53,12 +92,102 @@ pub fn extract_xz(compressed: &[u8]) -> std::io::Result<Vec<u8>> {
     return Ok(plain);
 }
 
+#[cfg(feature = "codecs")]
 pub fn compress_xz(plain: &[u8]) -> std::io::Result<Vec<u8>> {
     let mut compressor = XzEncoder::new(Vec::new(), 6);
     compressor.write_all(plain)?;
     return compressor.finish();
 }
 
+#[cfg(feature = "codecs")]
+pub fn extract_zstd(compressed: &[u8]) -> std::io::Result<Vec<u8>> {
+    return zstd::stream::decode_all(compressed);
+}
+
+#[cfg(feature = "codecs")]
+pub fn compress_zstd(plain: &[u8]) -> std::io::Result<Vec<u8>> {
+    return zstd::stream::encode_all(plain, 19);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Codec {
+    Raw,
+    Gz,
+    Xz,
+    Zstd,
+}
+
+impl Codec {
+    pub const ALL: [Codec; 4] = [Codec::Raw, Codec::Gz, Codec::Xz, Codec::Zstd];
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Codec::Raw => "txt",
+            Codec::Gz => "gz",
+            Codec::Xz => "xz",
+            Codec::Zstd => "zst",
+        }
+    }
+
+    pub fn from_extension(filename: &str) -> Option<Codec> {
+        let ext = filename.rsplit_once('.')?.1;
+        return Self::ALL.into_iter().find(|v| v.extension() == ext);
+    }
+
+    pub fn from_magic(data: &[u8]) -> Option<Codec> {
+        if data.starts_with(&[0x1F, 0x8B]) {
+            Some(Codec::Gz)
+        } else if data.starts_with(&[0xFD, b'7', b'z', b'X', b'Z', 0x00]) {
+            Some(Codec::Xz)
+        } else if data.starts_with(&[0x28, 0xB5, 0x2F, 0xFD]) {
+            Some(Codec::Zstd)
+        } else if data.iter().take(64).all(|v| v.is_ascii_hexdigit() || b":\r\n".contains(v)) {
+            Some(Codec::Raw)
+        } else {
+            None
+        }
+    }
+
+    // The extension wins when it is known, otherwise fall back to sniffing the content.
+    pub fn detect(filename: &str, data: &[u8]) -> Option<Codec> {
+        return Self::from_extension(filename).or_else(|| Self::from_magic(data));
+    }
+
+    #[cfg(feature = "codecs")]
+    pub fn extract(&self, compressed: &[u8]) -> std::io::Result<Vec<u8>> {
+        match self {
+            Codec::Raw => Ok(compressed.to_vec()),
+            Codec::Gz => extract_gz(compressed),
+            Codec::Xz => extract_xz(compressed),
+            Codec::Zstd => extract_zstd(compressed),
+        }
+    }
+
+    #[cfg(feature = "codecs")]
+    pub fn compress(&self, plain: &[u8]) -> std::io::Result<Vec<u8>> {
+        match self {
+            Codec::Raw => Ok(plain.to_vec()),
+            Codec::Gz => compress_gz(plain),
+            Codec::Xz => compress_xz(plain),
+            Codec::Zstd => compress_zstd(plain),
+        }
+    }
+}
+
+impl FromStr for Codec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "raw" | "txt" => Ok(Codec::Raw),
+            "gz" | "gzip" => Ok(Codec::Gz),
+            "xz" => Ok(Codec::Xz),
+            "zst" | "zstd" => Ok(Codec::Zstd),
+            _ => Err(format!("unknown codec: {}", s)),
+        }
+    }
+}
+
 pub struct Job {
     pub closure: Box<dyn FnOnce()>,
 }
@@ -111,7 +240,7 @@ impl<'a, T: Default + Copy> RandomItemGenerator<'a, T> {
         let mut pool: Vec<T> = vec![Default::default(); buffer_size];
         let slice = pool.as_mut_slice();
         let ptr: *mut u8 = slice.as_mut_ptr() as *mut u8;
-        let len: usize = size_of::<T>()*slice.len();
+        let len: usize = std::mem::size_of_val(slice);
 
         let memory: &mut [u8] = unsafe { slice::from_raw_parts_mut(ptr, len) };
 
@@ -151,14 +280,37 @@ pub struct HashAndPassword {
 }
 
 pub fn hash_password(v: &mut HashAndPassword) -> Result<(), Utf8Error> {
-    let password: &str = std::str::from_utf8(v.password.as_slice())?;
-    let raw = encode_to_utf16le(password);
+    v.hash = md4_fast::ntlm_utf8(v.password.as_slice())?;
+    Ok(())
+}
 
-    let mut hasher = Md4::new();
-    hasher.update(raw);
-    v.hash = hasher.finalize().into();
+// Hashes up to 16 passwords per SIMD pass. `valid[i]` is set to whether item i is UTF-8; the
+// hash of an invalid item is left as it was.
+pub fn hash_password_batch(items: &mut [HashAndPassword], valid: &mut [bool]) {
+    assert_eq!(items.len(), valid.len());
+
+    let mut hashes = [[0u8; 16]; 16];
+    for (chunk, ok) in items.chunks_mut(16).zip(valid.chunks_mut(16)) {
+        let mut passwords: [&str; 16] = [""; 16];
+        let mut slots = [0usize; 16];
+        let mut n = 0;
+        for (i, hp) in chunk.iter().enumerate() {
+            ok[i] = match std::str::from_utf8(hp.password.as_slice()) {
+                Ok(v) => {
+                    passwords[n] = v;
+                    slots[n] = i;
+                    n += 1;
+                    true
+                }
+                Err(_) => false,
+            };
+        }
+        md4_fast::ntlm_batch(&passwords[..n], &mut hashes[..n]);
 
-    Ok(())
+        for (i, hash) in slots[..n].iter().zip(hashes[..n].iter()) {
+            chunk[*i].hash = *hash;
+        }
+    }
 }
 
 pub trait InterpolationSearch<T> {
@@ -173,56 +325,55 @@ impl InterpolationSearch<HASH> for [HASH] {
         #[cfg(debug_assertions)]
         let _view_hash = String::from("");
 
-        let slope: u128 = u128::MAX/self.len() as u128;
+        let _len = self.len();
+        if _len == 0 || key < &self[0] {
+            return Err(0);
+        }
+        if key > &self[_len-1] {
+            return Err(_len);
+        }
+
+        // Interpolates between the first and last record rather than over the whole key space,
+        // so an index of a prefix interval guesses as well as a full one.
+        let first = u128::from_be_bytes(self[0]);
+        let last = u128::from_be_bytes(self[_len-1]);
+        let slope: u128 = (last-first)/_len as u128 + 1;
         let key_as_u128 = u128::from_be_bytes(*key);
 
-        let guess: usize = (key_as_u128/slope) as usize;
+        let guess: usize = usize::min(((key_as_u128-first)/slope) as usize, _len-1);
         let mut step = 1usize;
 
         let mut lo = 0usize;
-        let mut hi = self.len()-1;
-        let _len = self.len();
+        let mut hi = _len-1;
 
         #[cfg(debug_assertions)]
         let _view_hash = HASH_to_hex(&self[guess]);
 
+        // self[0] <= key <= self[_len-1], so both gallops stop inside the index.
         let mut i = guess;
         if key < &self[i] {
-            while key < &self[i] && i < _len {
+            while key < &self[i] {
                 #[cfg(debug_assertions)]
                 let _view_hash = HASH_to_hex(&self[i]);
                 hi = i;
-                i = match i.checked_sub(step) {
-                    None => break,
-                    Some(v) => v,
-                };
+                i = i.saturating_sub(step);
                 step <<= 1;
             }
-            #[cfg(debug_assertions)]
-            let _view_hash = HASH_to_hex(&self[i]);
             lo = i;
         } else {
             while key > &self[i] {
                 #[cfg(debug_assertions)]
                 let _view_hash = HASH_to_hex(&self[i]);
                 lo = i;
-                i += step;
+                i = usize::min(i+step, _len-1);
                 step <<= 1;
             }
-            #[cfg(debug_assertions)]
-            let _view_hash = HASH_to_hex(&self[i]);
             hi = i;
         }
 
-        if i < _len {
-            match self[lo..hi+1].binary_search(key) {
-                Ok(v) => Ok(lo+v),
-                Err(v) => Err(lo+v),
-            }
-        } else if i == _len {
-            Err(i)
-        } else {
-            Err(0)
+        match self[lo..hi+1].binary_search(key) {
+            Ok(v) => Ok(lo+v),
+            Err(v) => Err(lo+v),
         }
     }
 }
@@ -232,18 +383,22 @@ pub struct HashRange {
     pub range: u32,
     pub etag: u64,
     pub timestamp: i64,
+    pub codec: Codec,
     pub compressed: Vec<u8>,
 }
 
 impl HashRange {
-    pub const EXTENSION: &'static str = "gz";
-
     pub fn name(&self) -> String {
-        return format!("{:05X}.{}", self.range, Self::EXTENSION);
+        return format!("{:05X}.{}", self.range, self.codec.extension());
+    }
+
+    pub fn filename(&self) -> String {
+        return format!("{:05X}_{:016X}.{}", self.range, self.etag, self.codec.extension());
     }
 
 }
 
+#[cfg(feature = "download")]
 pub async fn download_range(client: &reqwest::Client, range: u32) -> Result<HashRange, DownloadError> {
     let base_url = "https://api.pwnedpasswords.com/range/X?mode=ntlm";
     let t = format!("{:05X}", range);
@@ -258,17 +413,31 @@ pub async fn download_range(client: &reqwest::Client, range: u32) -> Result<Hash
         return Err(DownloadError{ range });
     }
     let response = r.unwrap();
+    // Rate limiting (429) and outages (503) answer with an error page and without the headers.
+    if !response.status().is_success() {
+        return Err(DownloadError{range});
+    }
 
     let h = response.headers();
-    let mut etag = h.get("etag").unwrap().to_str().unwrap();
-    let prefix = "W/\"0x";
-    if etag.starts_with(prefix) {
-        etag = &etag[prefix.len()..etag.len()-1]
+    let mut etag = match h.get("etag").and_then(|v| v.to_str().ok()) {
+        Some(v) => v,
+        None => return Err(DownloadError{range}),
+    };
+    if let Some(v) = etag.strip_prefix("W/\"0x") {
+        etag = v.strip_suffix('"').unwrap_or(v);
     }
-    let etag_u64 = u64::from_str_radix(etag, 16).unwrap();
-
-    let t = h.get("last-modified").unwrap().to_str().unwrap();
-    let timestamp = DateTime::parse_from_rfc2822(t).unwrap().timestamp();
+    let etag_u64 = match u64::from_str_radix(etag, 16) {
+        Ok(v) => v,
+        Err(_) => return Err(DownloadError{range}),
+    };
+
+    let modified = h.get("last-modified")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| DateTime::parse_from_rfc2822(v).ok());
+    let timestamp = match modified {
+        Some(v) => v.timestamp(),
+        None => return Err(DownloadError{range}),
+    };
 
     let r = response.bytes().await;
     if r.is_err() {
@@ -281,6 +450,7 @@ pub async fn download_range(client: &reqwest::Client, range: u32) -> Result<Hash
         range,
         etag: etag_u64,
         timestamp,
+        codec: Codec::Gz,
         compressed: content,
     })
 }
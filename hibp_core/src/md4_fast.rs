@@ -1,12 +1,14 @@
-// https://rosettacode.org/wiki/MD4#Rust
-// MD4, based on RFC 1186 and RFC 1320.
+// MD4 and NTLM, following RFC 1320. The rounds are generic over `Lanes` so the same code
+// hashes one message with plain u32 arithmetic or 4, 8 or 16 at once with SSE2, AVX2 or
+// AVX-512, picked at runtime by `Isa::detect`. NTLM passwords that fit into one block skip the
+// UTF-16LE buffer and the generic padding.
 //
-// https://www.ietf.org/rfc/rfc1186.txt
 // https://tools.ietf.org/html/rfc1320
 //
 
-use std::fmt::Write;
-use std::mem;
+use std::str::Utf8Error;
+
+use crate::HASH;
 
 // Let not(X) denote the bit-wise complement of X.
 // Let X v Y denote the bit-wise OR of X and Y.
@@ -14,28 +16,163 @@ use std::mem;
 // Let XY denote the bit-wise AND of X and Y.
 
 // f(X,Y,Z)  =  XY v not(X)Z
+#[inline(always)]
 fn f(x: u32, y: u32, z: u32) -> u32 {
     (x & y) | (!x & z)
 }
 
 // g(X,Y,Z)  =  XY v XZ v YZ
+#[inline(always)]
 fn g(x: u32, y: u32, z: u32) -> u32 {
     (x & y) | (x & z) | (y & z)
 }
 
 // h(X,Y,Z)  =  X xor Y xor Z
+#[inline(always)]
 fn h(x: u32, y: u32, z: u32) -> u32 {
     x ^ y ^ z
 }
 
+// The rounds are written once against `Lanes`, which is a single u32 for the scalar path and a
+// SIMD register of 4, 8 or 16 independent messages otherwise.
+trait Lanes: Copy {
+    const LANES: usize;
+    unsafe fn splat(v: u32) -> Self;
+    unsafe fn load(p: *const u32) -> Self;
+    unsafe fn store(self, p: *mut u32);
+    unsafe fn add(self, o: Self) -> Self;
+    unsafe fn rotl(self, s: u32) -> Self;
+    unsafe fn f(x: Self, y: Self, z: Self) -> Self;
+    unsafe fn g(x: Self, y: Self, z: Self) -> Self;
+    unsafe fn h(x: Self, y: Self, z: Self) -> Self;
+}
+
+impl Lanes for u32 {
+    const LANES: usize = 1;
+    #[inline(always)]
+    unsafe fn splat(v: u32) -> Self { v }
+    #[inline(always)]
+    unsafe fn load(p: *const u32) -> Self { *p }
+    #[inline(always)]
+    unsafe fn store(self, p: *mut u32) { *p = self }
+    #[inline(always)]
+    unsafe fn add(self, o: Self) -> Self { self.wrapping_add(o) }
+    #[inline(always)]
+    unsafe fn rotl(self, s: u32) -> Self { self.rotate_left(s) }
+    #[inline(always)]
+    unsafe fn f(x: Self, y: Self, z: Self) -> Self { f(x, y, z) }
+    #[inline(always)]
+    unsafe fn g(x: Self, y: Self, z: Self) -> Self { g(x, y, z) }
+    #[inline(always)]
+    unsafe fn h(x: Self, y: Self, z: Self) -> Self { h(x, y, z) }
+}
+
+#[cfg(target_arch = "x86_64")]
+mod x86 {
+    use std::arch::x86_64::*;
+    use super::Lanes;
+
+    impl Lanes for __m128i {
+        const LANES: usize = 4;
+        #[inline]
+        #[target_feature(enable = "sse2")]
+        unsafe fn splat(v: u32) -> Self { _mm_set1_epi32(v as i32) }
+        #[inline]
+        #[target_feature(enable = "sse2")]
+        unsafe fn load(p: *const u32) -> Self { _mm_loadu_si128(p as *const __m128i) }
+        #[inline]
+        #[target_feature(enable = "sse2")]
+        unsafe fn store(self, p: *mut u32) { _mm_storeu_si128(p as *mut __m128i, self) }
+        #[inline]
+        #[target_feature(enable = "sse2")]
+        unsafe fn add(self, o: Self) -> Self { _mm_add_epi32(self, o) }
+        #[inline]
+        #[target_feature(enable = "sse2")]
+        unsafe fn rotl(self, s: u32) -> Self {
+            _mm_or_si128(_mm_sll_epi32(self, _mm_cvtsi32_si128(s as i32)), _mm_srl_epi32(self, _mm_cvtsi32_si128(32 - s as i32)))
+        }
+        #[inline]
+        #[target_feature(enable = "sse2")]
+        unsafe fn f(x: Self, y: Self, z: Self) -> Self { _mm_or_si128(_mm_and_si128(x, y), _mm_andnot_si128(x, z)) }
+        #[inline]
+        #[target_feature(enable = "sse2")]
+        unsafe fn g(x: Self, y: Self, z: Self) -> Self {
+            _mm_or_si128(_mm_and_si128(x, _mm_or_si128(y, z)), _mm_and_si128(y, z))
+        }
+        #[inline]
+        #[target_feature(enable = "sse2")]
+        unsafe fn h(x: Self, y: Self, z: Self) -> Self { _mm_xor_si128(_mm_xor_si128(x, y), z) }
+    }
+
+    impl Lanes for __m256i {
+        const LANES: usize = 8;
+        #[inline]
+        #[target_feature(enable = "avx2")]
+        unsafe fn splat(v: u32) -> Self { _mm256_set1_epi32(v as i32) }
+        #[inline]
+        #[target_feature(enable = "avx2")]
+        unsafe fn load(p: *const u32) -> Self { _mm256_loadu_si256(p as *const __m256i) }
+        #[inline]
+        #[target_feature(enable = "avx2")]
+        unsafe fn store(self, p: *mut u32) { _mm256_storeu_si256(p as *mut __m256i, self) }
+        #[inline]
+        #[target_feature(enable = "avx2")]
+        unsafe fn add(self, o: Self) -> Self { _mm256_add_epi32(self, o) }
+        #[inline]
+        #[target_feature(enable = "avx2")]
+        unsafe fn rotl(self, s: u32) -> Self {
+            _mm256_or_si256(_mm256_sll_epi32(self, _mm_cvtsi32_si128(s as i32)), _mm256_srl_epi32(self, _mm_cvtsi32_si128(32 - s as i32)))
+        }
+        #[inline]
+        #[target_feature(enable = "avx2")]
+        unsafe fn f(x: Self, y: Self, z: Self) -> Self { _mm256_or_si256(_mm256_and_si256(x, y), _mm256_andnot_si256(x, z)) }
+        #[inline]
+        #[target_feature(enable = "avx2")]
+        unsafe fn g(x: Self, y: Self, z: Self) -> Self {
+            _mm256_or_si256(_mm256_and_si256(x, _mm256_or_si256(y, z)), _mm256_and_si256(y, z))
+        }
+        #[inline]
+        #[target_feature(enable = "avx2")]
+        unsafe fn h(x: Self, y: Self, z: Self) -> Self { _mm256_xor_si256(_mm256_xor_si256(x, y), z) }
+    }
+
+    // AVX-512 has native rotates and evaluates each round function in one ternary-logic op.
+    impl Lanes for __m512i {
+        const LANES: usize = 16;
+        #[inline]
+        #[target_feature(enable = "avx512f")]
+        unsafe fn splat(v: u32) -> Self { _mm512_set1_epi32(v as i32) }
+        #[inline]
+        #[target_feature(enable = "avx512f")]
+        unsafe fn load(p: *const u32) -> Self { _mm512_loadu_si512(p as *const __m512i) }
+        #[inline]
+        #[target_feature(enable = "avx512f")]
+        unsafe fn store(self, p: *mut u32) { _mm512_storeu_si512(p as *mut __m512i, self) }
+        #[inline]
+        #[target_feature(enable = "avx512f")]
+        unsafe fn add(self, o: Self) -> Self { _mm512_add_epi32(self, o) }
+        #[inline]
+        #[target_feature(enable = "avx512f")]
+        unsafe fn rotl(self, s: u32) -> Self { _mm512_rolv_epi32(self, _mm512_set1_epi32(s as i32)) }
+        #[inline]
+        #[target_feature(enable = "avx512f")]
+        unsafe fn f(x: Self, y: Self, z: Self) -> Self { _mm512_ternarylogic_epi32::<0xCA>(x, y, z) }
+        #[inline]
+        #[target_feature(enable = "avx512f")]
+        unsafe fn g(x: Self, y: Self, z: Self) -> Self { _mm512_ternarylogic_epi32::<0xE8>(x, y, z) }
+        #[inline]
+        #[target_feature(enable = "avx512f")]
+        unsafe fn h(x: Self, y: Self, z: Self) -> Self { _mm512_ternarylogic_epi32::<0x96>(x, y, z) }
+    }
+}
+
 // Round 1 macro
 // Let [A B C D i s] denote the operation
 //   A = (A + f(B,C,D) + X[i]) <<< s
 macro_rules! md4round1 {
     ( $a:expr, $b:expr, $c:expr, $d:expr, $i:expr, $s:expr, $x:expr) => {
         {
-            // Rust defaults to non-overflowing arithmetic, so we need to specify wrapping add.
-            $a = ($a.wrapping_add( f($b, $c, $d) ).wrapping_add( $x[$i] ) ).rotate_left($s);
+            $a = $a.add(Lanes::f($b, $c, $d)).add($x[$i]).rotl($s);
         }
     };
 }
@@ -46,7 +183,7 @@ macro_rules! md4round1 {
 macro_rules! md4round2 {
     ( $a:expr, $b:expr, $c:expr, $d:expr, $i:expr, $s:expr, $x:expr) => {
         {
-            $a = ($a.wrapping_add( g($b, $c, $d)).wrapping_add($x[$i]).wrapping_add(0x5a827999_u32)).rotate_left($s);
+            $a = $a.add(Lanes::g($b, $c, $d)).add($x[$i]).add(Lanes::splat(0x5a827999_u32)).rotl($s);
         }
     };
 }
@@ -57,140 +194,348 @@ macro_rules! md4round2 {
 macro_rules! md4round3 {
     ( $a:expr, $b:expr, $c:expr, $d:expr, $i:expr, $s:expr, $x:expr) => {
         {
-            $a = ($a.wrapping_add(h($b, $c, $d)).wrapping_add($x[$i]).wrapping_add(0x6ed9eba1_u32)).rotate_left($s);
+            $a = $a.add(Lanes::h($b, $c, $d)).add($x[$i]).add(Lanes::splat(0x6ed9eba1_u32)).rotl($s);
         }
     };
 }
 
-fn convert_byte_vec_to_u32(mut bytes: Vec<u8>) -> Vec<u32> {
-
-    bytes.shrink_to_fit();
-    let num_bytes = bytes.len();
-    let num_words = num_bytes / 4;
-    unsafe {
-        let words = Vec::from_raw_parts(bytes.as_mut_ptr() as *mut u32, num_words, num_words);
-        mem::forget(bytes);
-        words
-    }
-}
-
-// Returns a 128-bit MD4 hash as an array of four 32-bit words.
-// Based on RFC 1186 from https://www.ietf.org/rfc/rfc1186.txt
-pub fn md4<T: Into<Vec<u8>>>(input: T) -> [u32; 4] {
-
-    let mut bytes = input.into().to_vec();
-    let initial_bit_len = (bytes.len() << 3) as u64;
-
-    // Step 1. Append padding bits
-    // Append one '1' bit, then append 0 ≤ k < 512 bits '0', such that the resulting message
-    // length in bis is congruent to 448 (mod 512).
-    // Since our message is in bytes, we use one byte with a set high-order bit (0x80) plus
-    // a variable number of zero bytes.
-
-    // Append zeros
-    // Number of padding bytes needed is 448 bits (56 bytes) modulo 512 bits (64 bytes)
-    bytes.push(0x80_u8);
-    while (bytes.len() % 64) != 56 {
-        bytes.push(0_u8);
-    }
-
-    // Everything after this operates on 32-bit words, so reinterpret the buffer.
-    let mut w = convert_byte_vec_to_u32(bytes);
-
-    // Step 2. Append length
-    // A 64-bit representation of b (the length of the message before the padding bits were added)
-    // is appended to the result of the previous step, low-order bytes first.
-    w.push(initial_bit_len as u32); // Push low-order bytes first
-    w.push((initial_bit_len >> 32) as u32);
-
-    // Step 3. Initialize MD buffer
-    let mut a = 0x67452301_u32;
-    let mut b = 0xefcdab89_u32;
-    let mut c = 0x98badcfe_u32;
-    let mut d = 0x10325476_u32;
-
-    // Step 4. Process message in 16-word blocks
-    let n = w.len();
-    for i in 0..n / 16 {
-
-        // Select the next 512-bit (16-word) block to process.
-        let x = &w[i * 16..i * 16 + 16];
-
-        let aa = a;
-        let bb = b;
-        let cc = c;
-        let dd = d;
-
-        // [Round 1]
-        md4round1!(a, b, c, d, 0, 3, x);  // [A B C D 0 3]
-        md4round1!(d, a, b, c, 1, 7, x);  // [D A B C 1 7]
-        md4round1!(c, d, a, b, 2, 11, x); // [C D A B 2 11]
-        md4round1!(b, c, d, a, 3, 19, x); // [B C D A 3 19]
-        md4round1!(a, b, c, d, 4, 3, x);  // [A B C D 4 3]
-        md4round1!(d, a, b, c, 5, 7, x);  // [D A B C 5 7]
-        md4round1!(c, d, a, b, 6, 11, x); // [C D A B 6 11]
-        md4round1!(b, c, d, a, 7, 19, x); // [B C D A 7 19]
-        md4round1!(a, b, c, d, 8, 3, x);  // [A B C D 8 3]
-        md4round1!(d, a, b, c, 9, 7, x);  // [D A B C 9 7]
-        md4round1!(c, d, a, b, 10, 11, x);// [C D A B 10 11]
-        md4round1!(b, c, d, a, 11, 19, x);// [B C D A 11 19]
-        md4round1!(a, b, c, d, 12, 3, x); // [A B C D 12 3]
-        md4round1!(d, a, b, c, 13, 7, x); // [D A B C 13 7]
-        md4round1!(c, d, a, b, 14, 11, x);// [C D A B 14 11]
-        md4round1!(b, c, d, a, 15, 19, x);// [B C D A 15 19]
-
-        // [Round 2]
-        md4round2!(a, b, c, d, 0, 3, x);  //[A B C D 0  3]
-        md4round2!(d, a, b, c, 4, 5, x);  //[D A B C 4  5]
-        md4round2!(c, d, a, b, 8, 9, x);  //[C D A B 8  9]
-        md4round2!(b, c, d, a, 12, 13, x);//[B C D A 12 13]
-        md4round2!(a, b, c, d, 1, 3, x);  //[A B C D 1  3]
-        md4round2!(d, a, b, c, 5, 5, x);  //[D A B C 5  5]
-        md4round2!(c, d, a, b, 9, 9, x);  //[C D A B 9  9]
-        md4round2!(b, c, d, a, 13, 13, x);//[B C D A 13 13]
-        md4round2!(a, b, c, d, 2, 3, x);  //[A B C D 2  3]
-        md4round2!(d, a, b, c, 6, 5, x);  //[D A B C 6  5]
-        md4round2!(c, d, a, b, 10, 9, x); //[C D A B 10 9]
-        md4round2!(b, c, d, a, 14, 13, x);//[B C D A 14 13]
-        md4round2!(a, b, c, d, 3, 3, x);  //[A B C D 3  3]
-        md4round2!(d, a, b, c, 7, 5, x);  //[D A B C 7  5]
-        md4round2!(c, d, a, b, 11, 9, x); //[C D A B 11 9]
-        md4round2!(b, c, d, a, 15, 13, x);//[B C D A 15 13]
-
-        // [Round 3]
-        md4round3!(a, b, c, d, 0, 3, x);  //[A B C D 0  3]
-        md4round3!(d, a, b, c, 8, 9, x);  //[D A B C 8  9]
-        md4round3!(c, d, a, b, 4, 11, x); //[C D A B 4  11]
-        md4round3!(b, c, d, a, 12, 15, x);//[B C D A 12 15]
-        md4round3!(a, b, c, d, 2, 3, x);  //[A B C D 2  3]
-        md4round3!(d, a, b, c, 10, 9, x); //[D A B C 10 9]
-        md4round3!(c, d, a, b, 6, 11, x); //[C D A B 6  11]
-        md4round3!(b, c, d, a, 14, 15, x);//[B C D A 14 15]
-        md4round3!(a, b, c, d, 1, 3, x);  //[A B C D 1  3]
-        md4round3!(d, a, b, c, 9, 9, x);  //[D A B C 9  9]
-        md4round3!(c, d, a, b, 5, 11, x); //[C D A B 5  11]
-        md4round3!(b, c, d, a, 13, 15, x);//[B C D A 13 15]
-        md4round3!(a, b, c, d, 3, 3, x);  //[A B C D 3  3]
-        md4round3!(d, a, b, c, 11, 9, x); //[D A B C 11 9]
-        md4round3!(c, d, a, b, 7, 11, x); //[C D A B 7  11]
-        md4round3!(b, c, d, a, 15, 15, x);//[B C D A 15 15]
-
-        a = a.wrapping_add(aa);
-        b = b.wrapping_add(bb);
-        c = c.wrapping_add(cc);
-        d = d.wrapping_add(dd);
-    }
-
-    // Step 5. Output
-    // The message digest produced as output is A, B, C, D. That is, we begin with the low-order
-    // byte of A, and end with the high-order byte of D.
-    [u32::from_be(a), u32::from_be(b), u32::from_be(c), u32::from_be(d)]
-}
-
-fn digest_to_str(digest: &[u32]) -> String {
-    let mut s = String::new();
-    for &word in digest {
-        write!(&mut s, "{:08x}", word).unwrap();
-    }
-    s
-}
\ No newline at end of file
+const INIT: [u32; 4] = [0x67452301_u32, 0xefcdab89_u32, 0x98badcfe_u32, 0x10325476_u32];
+
+// Processes one 16-word block per lane. `x[i]` holds word i of every lane.
+#[inline(always)]
+unsafe fn compress<V: Lanes>(state: &mut [V; 4], x: &[V; 16]) {
+    let [mut a, mut b, mut c, mut d] = *state;
+
+    // [Round 1]
+    md4round1!(a, b, c, d, 0, 3, x);  // [A B C D 0 3]
+    md4round1!(d, a, b, c, 1, 7, x);  // [D A B C 1 7]
+    md4round1!(c, d, a, b, 2, 11, x); // [C D A B 2 11]
+    md4round1!(b, c, d, a, 3, 19, x); // [B C D A 3 19]
+    md4round1!(a, b, c, d, 4, 3, x);  // [A B C D 4 3]
+    md4round1!(d, a, b, c, 5, 7, x);  // [D A B C 5 7]
+    md4round1!(c, d, a, b, 6, 11, x); // [C D A B 6 11]
+    md4round1!(b, c, d, a, 7, 19, x); // [B C D A 7 19]
+    md4round1!(a, b, c, d, 8, 3, x);  // [A B C D 8 3]
+    md4round1!(d, a, b, c, 9, 7, x);  // [D A B C 9 7]
+    md4round1!(c, d, a, b, 10, 11, x);// [C D A B 10 11]
+    md4round1!(b, c, d, a, 11, 19, x);// [B C D A 11 19]
+    md4round1!(a, b, c, d, 12, 3, x); // [A B C D 12 3]
+    md4round1!(d, a, b, c, 13, 7, x); // [D A B C 13 7]
+    md4round1!(c, d, a, b, 14, 11, x);// [C D A B 14 11]
+    md4round1!(b, c, d, a, 15, 19, x);// [B C D A 15 19]
+
+    // [Round 2]
+    md4round2!(a, b, c, d, 0, 3, x);  //[A B C D 0  3]
+    md4round2!(d, a, b, c, 4, 5, x);  //[D A B C 4  5]
+    md4round2!(c, d, a, b, 8, 9, x);  //[C D A B 8  9]
+    md4round2!(b, c, d, a, 12, 13, x);//[B C D A 12 13]
+    md4round2!(a, b, c, d, 1, 3, x);  //[A B C D 1  3]
+    md4round2!(d, a, b, c, 5, 5, x);  //[D A B C 5  5]
+    md4round2!(c, d, a, b, 9, 9, x);  //[C D A B 9  9]
+    md4round2!(b, c, d, a, 13, 13, x);//[B C D A 13 13]
+    md4round2!(a, b, c, d, 2, 3, x);  //[A B C D 2  3]
+    md4round2!(d, a, b, c, 6, 5, x);  //[D A B C 6  5]
+    md4round2!(c, d, a, b, 10, 9, x); //[C D A B 10 9]
+    md4round2!(b, c, d, a, 14, 13, x);//[B C D A 14 13]
+    md4round2!(a, b, c, d, 3, 3, x);  //[A B C D 3  3]
+    md4round2!(d, a, b, c, 7, 5, x);  //[D A B C 7  5]
+    md4round2!(c, d, a, b, 11, 9, x); //[C D A B 11 9]
+    md4round2!(b, c, d, a, 15, 13, x);//[B C D A 15 13]
+
+    // [Round 3]
+    md4round3!(a, b, c, d, 0, 3, x);  //[A B C D 0  3]
+    md4round3!(d, a, b, c, 8, 9, x);  //[D A B C 8  9]
+    md4round3!(c, d, a, b, 4, 11, x); //[C D A B 4  11]
+    md4round3!(b, c, d, a, 12, 15, x);//[B C D A 12 15]
+    md4round3!(a, b, c, d, 2, 3, x);  //[A B C D 2  3]
+    md4round3!(d, a, b, c, 10, 9, x); //[D A B C 10 9]
+    md4round3!(c, d, a, b, 6, 11, x); //[C D A B 6  11]
+    md4round3!(b, c, d, a, 14, 15, x);//[B C D A 14 15]
+    md4round3!(a, b, c, d, 1, 3, x);  //[A B C D 1  3]
+    md4round3!(d, a, b, c, 9, 9, x);  //[D A B C 9  9]
+    md4round3!(c, d, a, b, 5, 11, x); //[C D A B 5  11]
+    md4round3!(b, c, d, a, 13, 15, x);//[B C D A 13 15]
+    md4round3!(a, b, c, d, 3, 3, x);  //[A B C D 3  3]
+    md4round3!(d, a, b, c, 11, 9, x); //[D A B C 11 9]
+    md4round3!(c, d, a, b, 7, 11, x); //[C D A B 7  11]
+    md4round3!(b, c, d, a, 15, 15, x);//[B C D A 15 15]
+
+    state[0] = state[0].add(a);
+    state[1] = state[1].add(b);
+    state[2] = state[2].add(c);
+    state[3] = state[3].add(d);
+}
+
+// The message digest produced as output is A, B, C, D. That is, we begin with the low-order
+// byte of A, and end with the high-order byte of D.
+fn digest(state: &[u32; 4]) -> HASH {
+    let mut out: HASH = [0u8; 16];
+    for (i, v) in state.iter().enumerate() {
+        out[4*i..4*i+4].copy_from_slice(&v.to_le_bytes());
+    }
+    return out;
+}
+
+fn compress_block(state: &mut [u32; 4], block: &[u8]) {
+    let mut x = [0u32; 16];
+    for (i, w) in block.chunks_exact(4).enumerate() {
+        x[i] = u32::from_le_bytes(w.try_into().unwrap());
+    }
+    unsafe { compress(state, &x) };
+}
+
+// MD4 of an arbitrary message; the padding is assembled on the stack.
+pub fn md4(input: &[u8]) -> HASH {
+    let mut state = INIT;
+
+    let full = input.len() / 64 * 64;
+    for block in input[..full].chunks_exact(64) {
+        compress_block(&mut state, block);
+    }
+
+    // Append one '1' bit, then zeros up to 56 mod 64 bytes, then the 64-bit bit length.
+    let rest = &input[full..];
+    let mut tail = [0u8; 128];
+    tail[..rest.len()].copy_from_slice(rest);
+    tail[rest.len()] = 0x80;
+    let tail_len = if rest.len() < 56 { 64 } else { 128 };
+    tail[tail_len-8..tail_len].copy_from_slice(&((input.len() as u64) << 3).to_le_bytes());
+    for block in tail[..tail_len].chunks_exact(64) {
+        compress_block(&mut state, block);
+    }
+
+    return digest(&state);
+}
+
+// NTLM hashes the UTF-16LE encoding, so up to 27 code units fit into a single block next to the
+// padding byte and the length.
+pub const NTLM_MAX_CHARS: usize = 27;
+
+// Builds the single padded block for `password`, or None if it needs more than one.
+pub fn ntlm_block(password: &str) -> Option<[u32; 16]> {
+    let mut x = [0u32; 16];
+    let mut n = 0usize;
+    for unit in password.encode_utf16() {
+        if n == NTLM_MAX_CHARS {
+            return None;
+        }
+        x[n/2] |= (unit as u32) << (16*(n%2));
+        n += 1;
+    }
+    x[n/2] |= 0x80 << (16*(n%2));
+    x[14] = (n as u32) << 4;
+    return Some(x);
+}
+
+pub fn ntlm(password: &str) -> HASH {
+    match ntlm_block(password) {
+        Some(x) => {
+            let mut state = INIT;
+            unsafe { compress(&mut state, &x) };
+            digest(&state)
+        }
+        None => {
+            let mut hasher = NtlmHasher::default();
+            hasher.update(password);
+            hasher.finalize_reset()
+        }
+    }
+}
+
+// Validates and hashes in one go; the UTF-16LE encoding is never materialized.
+pub fn ntlm_utf8(password: &[u8]) -> Result<HASH, Utf8Error> {
+    return Ok(ntlm(std::str::from_utf8(password)?));
+}
+
+// Streams UTF-16LE code units straight into MD4 blocks, for passwords of any length. A block
+// holds a whole number of code units, so a unit never straddles two blocks.
+#[derive(Debug, Clone)]
+pub struct NtlmHasher {
+    state: [u32; 4],
+    block: [u8; 64],
+    len: usize,
+    total: u64,
+}
+
+impl Default for NtlmHasher {
+    fn default() -> Self {
+        Self {
+            state: INIT,
+            block: [0u8; 64],
+            len: 0,
+            total: 0,
+        }
+    }
+}
+
+impl NtlmHasher {
+    pub fn update(&mut self, password: &str) {
+        for unit in password.encode_utf16() {
+            self.block[self.len..self.len+2].copy_from_slice(&unit.to_le_bytes());
+            self.len += 2;
+            self.total += 2;
+            if self.len == 64 {
+                compress_block(&mut self.state, &self.block);
+                self.len = 0;
+            }
+        }
+    }
+
+    pub fn finalize_reset(&mut self) -> HASH {
+        self.block[self.len] = 0x80;
+        self.block[self.len+1..].fill(0);
+        if self.len >= 56 {
+            compress_block(&mut self.state, &self.block);
+            self.block.fill(0);
+        }
+        self.block[56..64].copy_from_slice(&(self.total << 3).to_le_bytes());
+        compress_block(&mut self.state, &self.block);
+
+        let hash = digest(&self.state);
+        *self = Self::default();
+        return hash;
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Isa {
+    Scalar,
+    Sse2,
+    Avx2,
+    Avx512,
+}
+
+impl Isa {
+    pub fn detect() -> Self {
+        #[cfg(target_arch = "x86_64")]
+        {
+            if is_x86_feature_detected!("avx512f") {
+                return Isa::Avx512;
+            }
+            if is_x86_feature_detected!("avx2") {
+                return Isa::Avx2;
+            }
+            if is_x86_feature_detected!("sse2") {
+                return Isa::Sse2;
+            }
+        }
+        return Isa::Scalar;
+    }
+
+    pub fn is_supported(&self) -> bool {
+        #[cfg(target_arch = "x86_64")]
+        {
+            return match self {
+                Isa::Scalar => true,
+                Isa::Sse2 => is_x86_feature_detected!("sse2"),
+                Isa::Avx2 => is_x86_feature_detected!("avx2"),
+                Isa::Avx512 => is_x86_feature_detected!("avx512f"),
+            };
+        }
+        #[cfg(not(target_arch = "x86_64"))]
+        return *self == Isa::Scalar;
+    }
+
+    pub fn lanes(&self) -> usize {
+        match self {
+            Isa::Scalar => 1,
+            Isa::Sse2 => 4,
+            Isa::Avx2 => 8,
+            Isa::Avx512 => 16,
+        }
+    }
+}
+
+// Hashes `V::LANES` single-block messages at once. Blocks are transposed so that register i
+// holds word i of every message.
+#[inline(always)]
+unsafe fn ntlm_lanes<V: Lanes>(blocks: &[[u32; 16]], out: &mut [HASH]) {
+    let mut x = [0u32; 16*16];
+    for (lane, block) in blocks.iter().enumerate() {
+        for (i, w) in block.iter().enumerate() {
+            x[i*V::LANES + lane] = *w;
+        }
+    }
+
+    let mut w = [V::splat(0); 16];
+    for (i, v) in w.iter_mut().enumerate() {
+        *v = V::load(x.as_ptr().add(i*V::LANES));
+    }
+    let mut state = [V::splat(INIT[0]), V::splat(INIT[1]), V::splat(INIT[2]), V::splat(INIT[3])];
+    compress(&mut state, &w);
+
+    let mut words = [0u32; 4*16];
+    for (i, v) in state.iter().enumerate() {
+        v.store(words.as_mut_ptr().add(i*V::LANES));
+    }
+    for (lane, hash) in out.iter_mut().enumerate().take(blocks.len()) {
+        *hash = digest(&[words[lane], words[V::LANES+lane], words[2*V::LANES+lane], words[3*V::LANES+lane]]);
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "sse2")]
+unsafe fn ntlm_sse2(blocks: &[[u32; 16]], out: &mut [HASH]) {
+    ntlm_lanes::<std::arch::x86_64::__m128i>(blocks, out);
+}
+
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "avx2")]
+unsafe fn ntlm_avx2(blocks: &[[u32; 16]], out: &mut [HASH]) {
+    ntlm_lanes::<std::arch::x86_64::__m256i>(blocks, out);
+}
+
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "avx512f")]
+unsafe fn ntlm_avx512(blocks: &[[u32; 16]], out: &mut [HASH]) {
+    ntlm_lanes::<std::arch::x86_64::__m512i>(blocks, out);
+}
+
+// Hashes `blocks` (each prepared with `ntlm_block`) with the widest lanes `isa` offers. Panics if
+// `isa` is not supported by the running CPU.
+pub fn ntlm_blocks_with(isa: Isa, blocks: &[[u32; 16]], out: &mut [HASH]) {
+    assert!(isa.is_supported(), "{:?} is not supported on this CPU", isa);
+    assert_eq!(blocks.len(), out.len());
+
+    for (chunk, hashes) in blocks.chunks(isa.lanes()).zip(out.chunks_mut(isa.lanes())) {
+        match isa {
+            Isa::Scalar => unsafe { ntlm_lanes::<u32>(chunk, hashes) },
+            #[cfg(target_arch = "x86_64")]
+            Isa::Sse2 => unsafe { ntlm_sse2(chunk, hashes) },
+            #[cfg(target_arch = "x86_64")]
+            Isa::Avx2 => unsafe { ntlm_avx2(chunk, hashes) },
+            #[cfg(target_arch = "x86_64")]
+            Isa::Avx512 => unsafe { ntlm_avx512(chunk, hashes) },
+            #[cfg(not(target_arch = "x86_64"))]
+            _ => unreachable!(),
+        }
+    }
+}
+
+// NTLM of every password in `passwords`. Passwords longer than `NTLM_MAX_CHARS` take the
+// scalar multi-block path.
+pub fn ntlm_batch_with(isa: Isa, passwords: &[&str], out: &mut [HASH]) {
+    assert_eq!(passwords.len(), out.len());
+
+    let lanes = isa.lanes();
+    let mut blocks = [[0u32; 16]; 16];
+    let mut long = [false; 16];
+    let mut hashes = [[0u8; 16]; 16];
+    for (chunk, result) in passwords.chunks(lanes).zip(out.chunks_mut(lanes)) {
+        for (i, password) in chunk.iter().enumerate() {
+            long[i] = match ntlm_block(password) {
+                Some(v) => { blocks[i] = v; false }
+                None => { blocks[i] = [0u32; 16]; true }
+            };
+        }
+        ntlm_blocks_with(isa, &blocks[..chunk.len()], &mut hashes[..chunk.len()]);
+
+        for (i, password) in chunk.iter().enumerate() {
+            result[i] = if long[i] { ntlm(password) } else { hashes[i] };
+        }
+    }
+}
+
+pub fn ntlm_batch(passwords: &[&str], out: &mut [HASH]) {
+    ntlm_batch_with(Isa::detect(), passwords, out);
+}
@@ -0,0 +1,108 @@
+use std::time::{Duration, Instant};
+
+// Long running operations report through a `Progress` sink instead of printing. `total` and
+// `range` count ranges; `bytes` is what the step moved (downloaded or written). A failed step
+// that is retried reports `Failed` and later `Advanced` for the same range. `Paused` means no
+// work will start for `resume`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProgressEvent {
+    Started { task: &'static str, total: u64 },
+    Advanced { range: u32, bytes: u64 },
+    Failed { range: u32, error: String },
+    Paused { resume: Duration },
+    Finished,
+}
+
+pub trait Progress {
+    fn event(&mut self, event: ProgressEvent);
+}
+
+pub struct NoProgress;
+
+impl Progress for NoProgress {
+    fn event(&mut self, _event: ProgressEvent) {}
+}
+
+// Adapts the `FnMut(u32)` callbacks, which see every completed range and nothing else.
+pub struct RangeCallback<F: FnMut(u32)>(pub F);
+
+impl<F: FnMut(u32)> Progress for RangeCallback<F> {
+    fn event(&mut self, event: ProgressEvent) {
+        if let ProgressEvent::Advanced { range, .. } = event {
+            (self.0)(range);
+        }
+    }
+}
+
+// Running totals of an event stream, for renderers that show rates and an ETA.
+#[derive(Debug, Clone)]
+pub struct ProgressStats {
+    pub task: &'static str,
+    pub total: u64,
+    pub done: u64,
+    pub bytes: u64,
+    pub errors: u64,
+    pub started: Instant,
+    pub finished: Option<Instant>,
+}
+
+impl Default for ProgressStats {
+    fn default() -> Self {
+        Self {
+            task: "",
+            total: 0,
+            done: 0,
+            bytes: 0,
+            errors: 0,
+            started: Instant::now(),
+            finished: None,
+        }
+    }
+}
+
+impl ProgressStats {
+    pub fn record(&mut self, event: &ProgressEvent) {
+        match event {
+            ProgressEvent::Started { task, total } => {
+                *self = Self::default();
+                self.task = task;
+                self.total = *total;
+            }
+            ProgressEvent::Advanced { bytes, .. } => {
+                self.done += 1;
+                self.bytes += bytes;
+            }
+            ProgressEvent::Failed { .. } => self.errors += 1,
+            ProgressEvent::Paused { .. } => {}
+            ProgressEvent::Finished => self.finished = Some(Instant::now()),
+        }
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        return self.finished.unwrap_or_else(Instant::now) - self.started;
+    }
+
+    pub fn fraction(&self) -> f64 {
+        if self.total == 0 {
+            return 1.0;
+        }
+        return (self.done as f64 / self.total as f64).min(1.0);
+    }
+
+    // Ranges and bytes per second since `Started`.
+    pub fn rate(&self) -> (f64, f64) {
+        let seconds = self.elapsed().as_secs_f64();
+        if seconds <= 0.0 {
+            return (0.0, 0.0);
+        }
+        return (self.done as f64 / seconds, self.bytes as f64 / seconds);
+    }
+
+    pub fn eta(&self) -> Option<Duration> {
+        let (rate, _) = self.rate();
+        if rate <= 0.0 || self.done >= self.total {
+            return None;
+        }
+        return Some(Duration::from_secs_f64((self.total - self.done) as f64 / rate));
+    }
+}
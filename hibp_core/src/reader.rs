@@ -0,0 +1,69 @@
+use std::io;
+use std::io::ErrorKind;
+use std::sync::Arc;
+
+use crate::coverage::{Lookup, PrefixRange};
+use crate::db::HIBPDB;
+use crate::residency::IndexOptions;
+use crate::HASH;
+
+// Query-only handle. It never starts a runtime or touches the range store, and clones share one
+// set of mappings, so a single open index can serve any number of threads.
+#[derive(Clone)]
+pub struct Reader {
+    db: Arc<HIBPDB>,
+}
+
+impl Reader {
+    pub fn open(dbdir: String, options: IndexOptions) -> io::Result<Self> {
+        return Self::try_from(HIBPDB::open(dbdir, options)?);
+    }
+
+    pub fn coverage(&self) -> PrefixRange {
+        return self.db.coverage;
+    }
+
+    pub fn len(&self) -> usize {
+        return self.db.len();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.len() == 0;
+    }
+
+    pub fn find(&self, key: &HASH) -> io::Result<Result<usize, usize>> {
+        return self.db.find(key);
+    }
+
+    pub fn contains(&self, key: &HASH) -> io::Result<bool> {
+        return self.db.contains(key);
+    }
+
+    pub fn covers(&self, key: &HASH) -> bool {
+        return self.db.covers(key);
+    }
+
+    pub fn lookup(&self, key: &HASH) -> Lookup {
+        return self.db.lookup(key);
+    }
+
+    pub fn find_batch(&self, keys: &[HASH]) -> io::Result<Vec<Result<usize, usize>>> {
+        return self.db.find_batch(keys);
+    }
+
+    pub fn lookup_batch(&self, keys: &[HASH]) -> Vec<Lookup> {
+        return self.db.lookup_batch(keys);
+    }
+}
+
+// Settings such as `strategy` are taken from the database as it is when converted.
+impl TryFrom<HIBPDB> for Reader {
+    type Error = io::Error;
+
+    fn try_from(db: HIBPDB) -> io::Result<Self> {
+        if db.index.is_none() && db.shards.is_none() && db.compact.is_none() && db.filter.is_none() {
+            return Err(io::Error::new(ErrorKind::NotFound, format!("{}: no index found", db.dbdir)));
+        }
+        return Ok(Self { db: Arc::new(db) });
+    }
+}
@@ -0,0 +1,160 @@
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{ErrorKind, Read};
+use std::mem::size_of;
+use std::str::FromStr;
+
+use memmap2::{Advice, Mmap, MmapMut, MmapOptions};
+
+use crate::db::HIBPDB;
+use crate::verify::hash_prefix;
+use crate::HASH;
+
+const PAGE_SIZE: usize = 4096;
+const PRELOAD_CHUNK: usize = 1<<20;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum AccessPattern {
+    #[default]
+    Normal,
+    Sequential,
+    Random,
+}
+
+impl FromStr for AccessPattern {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "normal" => Ok(AccessPattern::Normal),
+            "sequential" => Ok(AccessPattern::Sequential),
+            "random" => Ok(AccessPattern::Random),
+            _ => Err(format!("unknown access pattern: {}", s)),
+        }
+    }
+}
+
+// How the index mmaps are opened. `populate` faults the whole mapping in at mmap time, while
+// `preload` touches it page by page afterwards; `lock` pins it so it is never paged out again.
+// `in_memory` copies the files into anonymous memory instead, refusing them when together they
+// take more than `memory_budget` bytes.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct IndexOptions {
+    pub access: AccessPattern,
+    pub populate: bool,
+    pub preload: bool,
+    pub lock: bool,
+    pub huge_pages: bool,
+    pub in_memory: bool,
+    pub memory_budget: Option<u64>,
+}
+
+impl IndexOptions {
+    pub(crate) fn load(&self, fd: &File) -> io::Result<MmapMut> {
+        let len = fd.metadata()?.len();
+        if let Some(budget) = self.memory_budget {
+            if len > budget {
+                return Err(io::Error::new(ErrorKind::OutOfMemory, format!("index needs {} bytes, budget is {}", len, budget)));
+            }
+        }
+
+        let mut mmap = MmapOptions::new().len(len as usize).map_anon()?;
+        // Advised before the copy so the pages are faulted in huge to begin with.
+        #[cfg(target_os = "linux")]
+        if self.huge_pages {
+            let _ = mmap.advise(Advice::HugePage);
+        }
+        let mut reader = fd;
+        reader.read_exact(&mut mmap)?;
+        return Ok(mmap);
+    }
+
+    // Maps `fd` read-only the way these options ask for, copied into memory or from the file.
+    pub(crate) fn map(&self, fd: &File) -> io::Result<Mmap> {
+        let mmap = if self.in_memory {
+            self.load(fd)?.make_read_only()?
+        } else {
+            let mut mmap_options = MmapOptions::new();
+            if self.populate {
+                mmap_options.populate();
+            }
+            unsafe { mmap_options.map(fd)? }
+        };
+        self.apply(&mmap)?;
+        return Ok(mmap);
+    }
+
+    // `load` checks every file on its own; this checks the files one HIBPDB loads together.
+    pub(crate) fn check_budget(&self, pathnames: &[String]) -> io::Result<()> {
+        let budget = match (self.in_memory, self.memory_budget) {
+            (true, Some(v)) => v,
+            _ => return Ok(()),
+        };
+        let len: u64 = pathnames.iter()
+            .filter_map(|v| fs::metadata(v).ok())
+            .map(|v| v.len())
+            .sum();
+        if len > budget {
+            return Err(io::Error::new(ErrorKind::OutOfMemory, format!("indexes need {} bytes, budget is {}", len, budget)));
+        }
+        Ok(())
+    }
+
+    pub(crate) fn apply(&self, mmap: &Mmap) -> io::Result<()> {
+        if mmap.is_empty() {
+            return Ok(());
+        }
+
+        match self.access {
+            AccessPattern::Normal => {}
+            AccessPattern::Sequential => mmap.advise(Advice::Sequential)?,
+            AccessPattern::Random => mmap.advise(Advice::Random)?,
+        }
+        // Only a hint: kernels without transparent huge pages for file mappings reject it.
+        #[cfg(target_os = "linux")]
+        if self.huge_pages {
+            let _ = mmap.advise(Advice::HugePage);
+        }
+        if self.preload {
+            preload(mmap, |_| {});
+        }
+        if self.lock {
+            mmap.lock()?;
+        }
+        Ok(())
+    }
+}
+
+// Reads one byte of every page, calling `f` with the number of bytes touched so far after each
+// chunk.
+pub fn preload<F>(arr: &[u8], mut f: F) where F: FnMut(usize) {
+    let mut off = 0;
+    while off < arr.len() {
+        let end = usize::min(off+PRELOAD_CHUNK, arr.len());
+        for page in (off..end).step_by(PAGE_SIZE) {
+            std::hint::black_box(arr[page]);
+        }
+        off = end;
+        f(off);
+    }
+}
+
+impl HIBPDB {
+
+    // Faults in index.bin, reporting the prefix of the last record that is resident, and the
+    // Eytzinger array when there is one.
+    pub fn preload<F>(&self, mut f: F) where F: FnMut(u32) {
+        if let Some(index) = &self.index {
+            let slice = index.as_slice();
+            preload(&index.mmap, |v| {
+                if let Some(last) = (v/size_of::<HASH>()).checked_sub(1).and_then(|i| slice.get(i)) {
+                    f(hash_prefix(last));
+                }
+            });
+        }
+        if let Some(eytzinger) = &self.eytzinger {
+            preload(&eytzinger.mmap, |_| {});
+        }
+    }
+}
@@ -0,0 +1,315 @@
+use std::fs;
+use std::io;
+use std::io::ErrorKind;
+use std::str::FromStr;
+
+use crate::coverage::Lookup;
+use crate::db::HIBPDB;
+use crate::{hash_password, HashAndPassword, HASH};
+
+// A subset of the hashcat rule language, applied to characters rather than bytes:
+//
+//   :  no-op              l  lowercase            u  uppercase
+//   c  capitalize         C  invert capitalize    t  toggle case
+//   TN toggle at N        r  reverse              d  duplicate
+//   $X append X           ^X prepend X            [  delete first
+//   ]  delete last        DN delete at N          'N truncate at N
+//   sXY replace X by Y    @X purge X
+//
+// Positions N are 0-9 then A-Z for 10-35. Two extensions cover what the language has no
+// function for: `?d` strips trailing digits and `?y` strips a trailing year 1900-2099. A rule
+// that does not apply (out of range position, nothing to strip) rejects the word.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RuleOp {
+    Noop,
+    Lower,
+    Upper,
+    Capitalize,
+    InvertCapitalize,
+    ToggleCase,
+    ToggleAt(usize),
+    Reverse,
+    Duplicate,
+    Append(char),
+    Prepend(char),
+    DeleteFirst,
+    DeleteLast,
+    DeleteAt(usize),
+    Truncate(usize),
+    Replace(char, char),
+    Purge(char),
+    StripDigits,
+    StripYear,
+}
+
+fn position(v: char) -> Option<usize> {
+    match v {
+        '0'..='9' => Some(v as usize - '0' as usize),
+        'A'..='Z' => Some(v as usize - 'A' as usize + 10),
+        _ => None,
+    }
+}
+
+fn toggle(v: char) -> char {
+    if v.is_lowercase() {
+        v.to_uppercase().next().unwrap_or(v)
+    } else {
+        v.to_lowercase().next().unwrap_or(v)
+    }
+}
+
+fn capitalize(word: &[char]) -> Vec<char> {
+    let mut out: Vec<char> = word.iter().flat_map(|v| v.to_lowercase()).collect();
+    if let Some(first) = out.first_mut() {
+        *first = first.to_uppercase().next().unwrap_or(*first);
+    }
+    return out;
+}
+
+impl RuleOp {
+    fn apply(&self, word: &mut Vec<char>) -> bool {
+        match *self {
+            RuleOp::Noop => {}
+            RuleOp::Lower => *word = word.iter().flat_map(|v| v.to_lowercase()).collect(),
+            RuleOp::Upper => *word = word.iter().flat_map(|v| v.to_uppercase()).collect(),
+            RuleOp::Capitalize => *word = capitalize(word),
+            RuleOp::InvertCapitalize => *word = capitalize(word).into_iter().map(toggle).collect(),
+            RuleOp::ToggleCase => word.iter_mut().for_each(|v| *v = toggle(*v)),
+            RuleOp::ToggleAt(n) => match word.get_mut(n) {
+                Some(v) => *v = toggle(*v),
+                None => return false,
+            },
+            RuleOp::Reverse => word.reverse(),
+            RuleOp::Duplicate => word.extend_from_within(..),
+            RuleOp::Append(v) => word.push(v),
+            RuleOp::Prepend(v) => word.insert(0, v),
+            RuleOp::DeleteFirst => {
+                if word.is_empty() {
+                    return false;
+                }
+                word.remove(0);
+            }
+            RuleOp::DeleteLast => {
+                if word.pop().is_none() {
+                    return false;
+                }
+            }
+            RuleOp::DeleteAt(n) => {
+                if n >= word.len() {
+                    return false;
+                }
+                word.remove(n);
+            }
+            RuleOp::Truncate(n) => {
+                if n >= word.len() {
+                    return false;
+                }
+                word.truncate(n);
+            }
+            RuleOp::Replace(from, to) => word.iter_mut().filter(|v| **v == from).for_each(|v| *v = to),
+            RuleOp::Purge(v) => word.retain(|c| *c != v),
+            RuleOp::StripDigits => {
+                let n = word.iter().rev().take_while(|v| v.is_ascii_digit()).count();
+                if n == 0 || n == word.len() {
+                    return false;
+                }
+                word.truncate(word.len() - n);
+            }
+            RuleOp::StripYear => {
+                if word.len() <= 4 {
+                    return false;
+                }
+                let tail: String = word[word.len()-4..].iter().collect();
+                match tail.parse::<u32>() {
+                    Ok(1900..=2099) if tail.chars().all(|v| v.is_ascii_digit()) => word.truncate(word.len() - 4),
+                    _ => return false,
+                }
+            }
+        }
+        return true;
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rule {
+    pub text: String,
+    pub ops: Vec<RuleOp>,
+}
+
+impl FromStr for Rule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut ops: Vec<RuleOp> = Vec::new();
+        let mut chars = s.chars();
+        let err = |what: &str| format!("invalid rule {:?}: {}", s, what);
+
+        while let Some(c) = chars.next() {
+            let mut arg = || chars.next().ok_or_else(|| err(&format!("'{}' needs an argument", c)));
+            let op = match c {
+                ' ' | '\t' => continue,
+                ':' => RuleOp::Noop,
+                'l' => RuleOp::Lower,
+                'u' => RuleOp::Upper,
+                'c' => RuleOp::Capitalize,
+                'C' => RuleOp::InvertCapitalize,
+                't' => RuleOp::ToggleCase,
+                'r' => RuleOp::Reverse,
+                'd' => RuleOp::Duplicate,
+                '[' => RuleOp::DeleteFirst,
+                ']' => RuleOp::DeleteLast,
+                '$' => RuleOp::Append(arg()?),
+                '^' => RuleOp::Prepend(arg()?),
+                '@' => RuleOp::Purge(arg()?),
+                's' => RuleOp::Replace(arg()?, arg()?),
+                'T' | 'D' | '\'' => {
+                    let n = arg()?;
+                    let n = position(n).ok_or_else(|| err(&format!("bad position '{}'", n)))?;
+                    match c {
+                        'T' => RuleOp::ToggleAt(n),
+                        'D' => RuleOp::DeleteAt(n),
+                        _ => RuleOp::Truncate(n),
+                    }
+                }
+                '?' => match arg()? {
+                    'd' => RuleOp::StripDigits,
+                    'y' => RuleOp::StripYear,
+                    v => return Err(err(&format!("unknown extension '?{}'", v))),
+                },
+                v => return Err(err(&format!("unsupported function '{}'", v))),
+            };
+            ops.push(op);
+        }
+
+        if ops.is_empty() {
+            return Err(err("empty"));
+        }
+        Ok(Self {
+            text: String::from(s.trim()),
+            ops,
+        })
+    }
+}
+
+impl Rule {
+    pub fn apply(&self, word: &str) -> Option<String> {
+        let mut chars: Vec<char> = word.chars().collect();
+        for op in &self.ops {
+            if !op.apply(&mut chars) {
+                return None;
+            }
+        }
+        return Some(chars.into_iter().collect());
+    }
+}
+
+// Trivial variants of a password: case changes, stripped digits and years, common suffixes
+// and leetspeak.
+pub const DEFAULT_RULES: &str = "\
+:
+l
+u
+c
+C
+t
+?d
+?y
+]
+[
+c ?d
+l ?d
+l ?y
+$!
+$1
+$1$2$3
+c $!
+c $1
+l sa@ se3 si1 so0
+sa@ se3 si1 so0 ss$
+sa4 se3 si1 so0 st7
+s@a s3e s1i s0o s$s
+s4a s3e s1i s0o s7t
+";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RuleSet {
+    pub rules: Vec<Rule>,
+}
+
+impl Default for RuleSet {
+    fn default() -> Self {
+        return RuleSet::parse(DEFAULT_RULES).unwrap();
+    }
+}
+
+impl RuleSet {
+    // One rule per line; blank lines and lines starting with '#' are skipped.
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut rules: Vec<Rule> = Vec::new();
+        for line in text.lines() {
+            if line.trim().is_empty() || line.starts_with('#') {
+                continue;
+            }
+            rules.push(line.parse()?);
+        }
+        Ok(Self { rules })
+    }
+
+    pub fn load(pathname: &str) -> io::Result<Self> {
+        let text = fs::read_to_string(pathname)?;
+        return RuleSet::parse(text.as_str()).map_err(|e| io::Error::new(ErrorKind::InvalidData, e));
+    }
+
+    // Every distinct candidate with the first rule producing it.
+    pub fn candidates<'r>(&'r self, word: &str) -> Vec<(&'r Rule, String)> {
+        let mut out: Vec<(&Rule, String)> = Vec::new();
+        for rule in &self.rules {
+            if let Some(candidate) = rule.apply(word) {
+                if !out.iter().any(|(_, v)| *v == candidate) {
+                    out.push((rule, candidate));
+                }
+            }
+        }
+        return out;
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RuleMatch {
+    pub rule: String,
+    pub candidate: String,
+    pub hash: HASH,
+    // Found, or NotCovered when the variant's prefix is outside the index.
+    pub lookup: Lookup,
+}
+
+impl HIBPDB {
+
+    // Looks up every variant `rules` derives from `password` and returns the ones present, and
+    // the ones the index can not answer for.
+    pub fn check_variants(&self, rules: &RuleSet, password: &str) -> Vec<RuleMatch> {
+        let mut hp = HashAndPassword {
+            hash: [0u8; 16],
+            password: Vec::new(),
+        };
+
+        let mut matches: Vec<RuleMatch> = Vec::new();
+        for (rule, candidate) in rules.candidates(password) {
+            hp.password.clear();
+            hp.password.extend_from_slice(candidate.as_bytes());
+            if hash_password(&mut hp).is_err() {
+                continue;
+            }
+            let lookup = self.lookup(&hp.hash);
+            if lookup != Lookup::NotFound {
+                matches.push(RuleMatch {
+                    rule: rule.text.clone(),
+                    candidate,
+                    hash: hp.hash,
+                    lookup,
+                });
+            }
+        }
+        return matches;
+    }
+}
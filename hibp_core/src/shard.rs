@@ -0,0 +1,194 @@
+use std::fs;
+use std::io;
+use std::io::ErrorKind;
+
+use crate::coverage::PrefixRange;
+use crate::db::{FileArray, FullIndexWriter, IndexSink};
+use crate::residency::IndexOptions;
+use crate::verify::hash_prefix;
+use crate::HASH;
+
+// One line per shard, in prefix order: `<first>-<last> <index file> <records>`. The files are
+// plain index.bin slices, so a shard can be copied to another host as is.
+pub const SHARD_MANIFEST: &str = "index.shards";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ShardEntry {
+    pub prefixes: PrefixRange,
+    pub filename: String,
+    pub records: u64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ShardManifest {
+    pub shards: Vec<ShardEntry>,
+}
+
+pub fn shard_filename(i: usize) -> String {
+    return format!("index.{:03}.bin", i);
+}
+
+// Splits `prefixes` into `n` intervals whose sizes differ by at most one range.
+pub fn split_prefixes(prefixes: PrefixRange, n: usize) -> Vec<PrefixRange> {
+    let n = n.clamp(1, prefixes.count());
+    let (size, rest) = (prefixes.count() / n, prefixes.count() % n);
+
+    let mut out: Vec<PrefixRange> = Vec::with_capacity(n);
+    let mut start = prefixes.start;
+    for i in 0..n {
+        let count = (size + usize::from(i < rest)) as u32;
+        out.push(PrefixRange { start, end: start + count - 1 });
+        start += count;
+    }
+    return out;
+}
+
+impl ShardManifest {
+    pub fn load(pathname: &str) -> io::Result<Self> {
+        let text = fs::read_to_string(pathname)?;
+        let invalid = |line: usize, msg: String| io::Error::new(ErrorKind::InvalidData, format!("{}:{}: {}", pathname, line, msg));
+
+        let mut shards: Vec<ShardEntry> = Vec::new();
+        for (i, line) in text.lines().enumerate() {
+            if line.trim().is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() != 3 {
+                return Err(invalid(i+1, String::from("expected <prefixes> <file> <records>")));
+            }
+            let prefixes: PrefixRange = fields[0].parse().map_err(|e| invalid(i+1, e))?;
+            let records = fields[2].parse::<u64>().map_err(|e| invalid(i+1, e.to_string()))?;
+            if shards.last().is_some_and(|v| v.prefixes.end >= prefixes.start) {
+                return Err(invalid(i+1, String::from("shards overlap or are out of order")));
+            }
+            shards.push(ShardEntry {
+                prefixes,
+                filename: String::from(fields[1]),
+                records,
+            });
+        }
+        Ok(Self { shards })
+    }
+
+    pub fn save(&self, pathname: &str) -> io::Result<()> {
+        let mut text = String::new();
+        for shard in &self.shards {
+            text += format!("{} {} {}\n", shard.prefixes, shard.filename, shard.records).as_str();
+        }
+        return fs::write(pathname, text);
+    }
+
+    pub fn route(&self, range: u32) -> Option<usize> {
+        let i = self.shards.partition_point(|v| v.prefixes.end < range);
+        return match self.shards.get(i) {
+            Some(shard) if shard.prefixes.contains(range) => Some(i),
+            _ => None,
+        };
+    }
+}
+
+pub struct Shard {
+    pub prefixes: PrefixRange,
+    // Position of the shard's first record in the concatenation of all shards.
+    pub base: usize,
+    pub index: FileArray<HASH>,
+}
+
+pub struct ShardSet {
+    pub manifest: ShardManifest,
+    pub shards: Vec<Shard>,
+}
+
+impl ShardSet {
+    pub fn open(dbdir: &str, options: &IndexOptions) -> io::Result<Self> {
+        let manifest = ShardManifest::load((String::from(dbdir)+"/"+SHARD_MANIFEST).as_str())?;
+
+        let mut shards: Vec<Shard> = Vec::with_capacity(manifest.shards.len());
+        let mut base = 0usize;
+        for entry in &manifest.shards {
+            let index = FileArray::open_with(String::from(dbdir)+"/"+entry.filename.as_str(), options)?;
+            if index.len() as u64 != entry.records {
+                return Err(io::Error::new(ErrorKind::InvalidData, format!("{}: {} records, manifest says {}", entry.filename, index.len(), entry.records)));
+            }
+            shards.push(Shard { prefixes: entry.prefixes, base, index });
+            base += entry.records as usize;
+        }
+        Ok(Self { manifest, shards })
+    }
+
+    pub fn len(&self) -> usize {
+        return self.shards.iter().map(|v| v.index.len()).sum();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.len() == 0;
+    }
+
+    // Positions are global, as if the shards were one index.bin. A hash outside every shard is
+    // reported at the end of the shard before it.
+    pub fn find(&self, key: &HASH) -> Result<usize, usize> {
+        let range = hash_prefix(key);
+        let i = match self.manifest.route(range) {
+            Some(v) => v,
+            None => {
+                let i = self.manifest.shards.partition_point(|v| v.prefixes.end < range);
+                return Err(self.shards[..i].iter().map(|v| v.index.len()).sum());
+            }
+        };
+        let shard = &self.shards[i];
+        // Interpolation assumes keys spread over the whole hash space, so shards use binary search.
+        return match shard.index.as_slice().binary_search(key) {
+            Ok(v) => Ok(shard.base + v),
+            Err(v) => Err(shard.base + v),
+        };
+    }
+}
+
+// Routes ranges to one FullIndexWriter per shard and writes the manifest on finish. Every file
+// is written as `<name>.tmp` for the caller to rename into place.
+pub struct ShardWriter {
+    pub manifest_path: String,
+    pub manifest: ShardManifest,
+    writers: Vec<FullIndexWriter>,
+}
+
+impl ShardWriter {
+    pub fn create(dbdir: &str, shards: &[PrefixRange]) -> io::Result<Self> {
+        let mut manifest = ShardManifest::default();
+        let mut writers: Vec<FullIndexWriter> = Vec::with_capacity(shards.len());
+        for (i, prefixes) in shards.iter().enumerate() {
+            let filename = shard_filename(i);
+            writers.push(FullIndexWriter::create((String::from(dbdir)+"/"+filename.as_str()+".tmp").as_str())?);
+            manifest.shards.push(ShardEntry {
+                prefixes: *prefixes,
+                filename,
+                records: 0,
+            });
+        }
+        Ok(Self {
+            manifest_path: String::from(dbdir)+"/"+SHARD_MANIFEST+".tmp",
+            manifest,
+            writers,
+        })
+    }
+}
+
+impl IndexSink for ShardWriter {
+    fn push_range(&mut self, range: u32, hashes: &[u8]) -> io::Result<()> {
+        let i = match self.manifest.route(range) {
+            Some(v) => v,
+            None => return Err(io::Error::new(ErrorKind::InvalidInput, format!("{:05X} is outside every shard", range))),
+        };
+        self.manifest.shards[i].records += (hashes.len() / 16) as u64;
+        return self.writers[i].push_range(range, hashes);
+    }
+
+    fn finish(self: Box<Self>) -> io::Result<()> {
+        let this = *self;
+        for writer in this.writers {
+            Box::new(writer).finish()?;
+        }
+        return this.manifest.save(this.manifest_path.as_str());
+    }
+}
@@ -0,0 +1,232 @@
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{BufRead, ErrorKind, Read, Write};
+use std::os::unix::fs::FileExt;
+use std::time::{Duration, UNIX_EPOCH};
+
+use regex::Regex;
+
+use crate::coverage::PrefixRange;
+use crate::db::{HIBPDB, RANGE_FILENAME};
+use crate::verify::{hash_prefix, RANGE_COUNT};
+use crate::{dir_list, Codec, HashRange, HASH, HASH_to_hex};
+
+pub(crate) enum StoredRange {
+    File(String),
+    // The archive's data file with the offset and length of the range.
+    Packed(File, u64, usize),
+}
+
+impl StoredRange {
+    pub(crate) fn read(&self) -> io::Result<Vec<u8>> {
+        match self {
+            StoredRange::File(pathname) => {
+                let mut buff: Vec<u8> = Vec::new();
+                File::open(pathname)?.read_to_end(&mut buff)?;
+                Ok(buff)
+            }
+            StoredRange::Packed(data, offset, length) => {
+                let mut buff = vec![0u8; *length];
+                data.read_exact_at(buff.as_mut_slice(), *offset)?;
+                Ok(buff)
+            }
+        }
+    }
+}
+
+#[cfg(feature = "build")]
+fn decode_range(filename: &str, buff: &[u8]) -> io::Result<Vec<u8>> {
+    match Codec::detect(filename, buff) {
+        Some(codec) => codec.extract(buff),
+        None => Err(io::Error::new(ErrorKind::InvalidInput, "unsupported file type")),
+    }
+}
+
+// Packs the `SUFFIX:COUNT` lines of a range into 16 byte hashes.
+#[cfg(feature = "build")]
+fn parse_range(range: u32, plain: &[u8]) -> io::Result<Vec<u8>> {
+    let mut buff: Vec<u8> = Vec::new();
+    let mut hash = vec![0u8; 16];
+    for v in plain.lines() {
+        let line = v?;
+        let t = hex::decode_to_slice(format!("{:05X}{}", range, &line[0..(32-5)]), hash.as_mut_slice());
+        match t {
+            Ok(_) => buff.extend(&hash),
+            Err(e) => return Err(io::Error::new(ErrorKind::InvalidInput, e.to_string())),
+        }
+    }
+    Ok(buff)
+}
+
+// The range store: one compressed `SUFFIX:COUNT` file per range, in range/ or in range.pack.
+impl HIBPDB {
+
+    pub fn save(&self, mut hr: HashRange) -> std::io::Result<()> {
+        if hr.codec != self.codec {
+            let plain = hr.codec.extract(hr.compressed.as_slice())?;
+            hr.compressed = self.codec.compress(plain.as_slice())?;
+            hr.codec = self.codec;
+        }
+
+        if let Some(archive) = self.archive()? {
+            return archive.write().unwrap().write(&hr);
+        }
+
+        let prefix: String = self.dbdir.clone()+"/range/";
+        let fname = hr.filename();
+
+        let path_tmp = prefix.clone()+"tmp."+fname.as_str();
+        let pathname = prefix+fname.as_str();
+        {
+            let mut fd = File::create(&path_tmp)?;
+            fd.write_all(hr.compressed.as_slice())?;
+            if hr.timestamp > 0 {
+                fd.set_modified(UNIX_EPOCH + Duration::from_secs(hr.timestamp as u64))?;
+            }
+        }
+        fs::rename(path_tmp, pathname)?;
+
+        Ok(())
+    }
+
+    pub fn range_map(&self) -> io::Result<Vec<String>> {
+        return self.range_map_for(self.prefixes);
+    }
+
+    // One filename per range; ranges outside `prefixes` are left empty and never required.
+    pub fn range_map_for(&self, prefixes: PrefixRange) -> io::Result<Vec<String>> {
+        let mut out: Vec<String> = vec![String::new(); RANGE_COUNT];
+
+        if let Some(archive) = self.archive()? {
+            let archive = archive.read().unwrap();
+            for (i, entry) in archive.entries.iter().enumerate() {
+                if !prefixes.contains(i as u32) {
+                    continue;
+                }
+                if !entry.is_present() {
+                    return Err(io::Error::new(ErrorKind::NotFound, format!("missing {:05X}", i)));
+                }
+                out[i] = entry.filename(i as u32);
+            }
+            return Ok(out);
+        }
+
+        let dir_range = self.dbdir.clone()+"/range/";
+
+        let mut ls = dir_list(dir_range.as_str())?;
+        ls.sort();
+        let re = Regex::new(RANGE_FILENAME).unwrap();
+
+        for filename in ls {
+            let cap = match re.captures(filename.as_str()) {
+                Some(v) => v,
+                None => continue,
+            };
+            let range = u32::from_str_radix(cap.get(1).unwrap().as_str(), 16).unwrap();
+            if !prefixes.contains(range) {
+                continue;
+            }
+            if !out[range as usize].is_empty() {
+                return Err(io::Error::new(ErrorKind::AlreadyExists, format!("duplicate {:05X}", range)));
+            }
+            out[range as usize] = filename;
+        }
+
+        if let Some(range) = (prefixes.start..=prefixes.end).find(|v| out[*v as usize].is_empty()) {
+            return Err(io::Error::new(ErrorKind::NotFound, format!("missing {:05X}", range)));
+        }
+
+        return Ok(out);
+    }
+
+    // Where the stored bytes of a range live, as something a blocking thread can read on its own.
+    pub(crate) fn locate(&self, range: u32, filename: &str) -> io::Result<StoredRange> {
+        if let Some(archive) = self.archive()? {
+            let archive = archive.read().unwrap();
+            return match archive.get(range) {
+                Some(entry) => Ok(StoredRange::Packed(archive.data.try_clone()?, entry.offset, entry.length as usize)),
+                None => Err(io::Error::new(ErrorKind::NotFound, format!("missing {:05X}", range))),
+            };
+        }
+        return Ok(StoredRange::File(self.dbdir.clone()+"/range/"+filename));
+    }
+
+    // Returns the stored (still compressed) bytes of a range, from the archive when there is one.
+    pub(crate) fn read_stored(&self, range: u32, filename: &str) -> io::Result<Vec<u8>> {
+        return self.locate(range, filename)?.read();
+    }
+
+    // File reads and decompression run on the blocking pool, not on the caller's runtime.
+    #[cfg(feature = "build")]
+    pub(crate) async fn read_range(&self, range_map: &[String], range: u32) -> io::Result<Vec<u8>> {
+        let filename = range_map[range as usize].clone();
+        let stored = self.locate(range, filename.as_str())?;
+
+        return tokio::task::spawn_blocking(move || decode_range(filename.as_str(), stored.read()?.as_slice()))
+            .await
+            .map_err(io::Error::other)?;
+    }
+
+    #[cfg(feature = "build")]
+    pub(crate) async fn extract_range(&self, range_map: &[String], range: u32) -> io::Result<Vec<u8>> {
+        let filename = range_map[range as usize].clone();
+        let stored = self.locate(range, filename.as_str())?;
+
+        return tokio::task::spawn_blocking(move || {
+            let plain = decode_range(filename.as_str(), stored.read()?.as_slice())?;
+            parse_range(range, plain.as_slice())
+        }).await.map_err(io::Error::other)?;
+    }
+
+    // The index only stores hashes; how often a password was seen comes from the `SUFFIX:COUNT`
+    // lines of its range file. Returns None when the hash is not in the range. range/ is listed
+    // once per db, so files saved or removed afterwards are only seen by a db opened later.
+    pub fn prevalence(&self, hash: &HASH) -> io::Result<Option<u64>> {
+        let range = hash_prefix(hash);
+        let filename = match self.archive()? {
+            Some(archive) => match archive.read().unwrap().get(range) {
+                Some(entry) => entry.filename(range),
+                None => return Err(io::Error::new(ErrorKind::NotFound, format!("missing {:05X}", range))),
+            },
+            None => {
+                let listing = match self.listing.get() {
+                    Some(v) => v,
+                    None => {
+                        let _ = self.listing.set(self.range_files()?);
+                        self.listing.get().unwrap()
+                    }
+                };
+                let files = &listing[range as usize];
+                match files.len() {
+                    0 => return Err(io::Error::new(ErrorKind::NotFound, format!("missing {:05X}", range))),
+                    1 => files[0].clone(),
+                    _ => return Err(io::Error::new(ErrorKind::AlreadyExists, format!("duplicate {:05X}", range))),
+                }
+            }
+        };
+
+        let buff = self.read_stored(range, filename.as_str())?;
+        let plain = match Codec::detect(filename.as_str(), buff.as_slice()) {
+            Some(codec) => codec.extract(buff.as_slice())?,
+            None => return Err(io::Error::new(ErrorKind::InvalidInput, "unsupported file type")),
+        };
+
+        let hex = HASH_to_hex(hash);
+        let suffix = &hex[5..];
+        for v in plain.lines() {
+            let line = v?;
+            let (s, count) = match line.trim_end().split_once(':') {
+                Some(t) => t,
+                None => continue,
+            };
+            if s.eq_ignore_ascii_case(suffix) {
+                return match count.parse::<u64>() {
+                    Ok(count) => Ok(Some(count)),
+                    Err(e) => Err(io::Error::new(ErrorKind::InvalidData, format!("{:05X}: {}", range, e))),
+                };
+            }
+        }
+        return Ok(None);
+    }
+}
@@ -0,0 +1,124 @@
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use chrono::{NaiveTime, Timelike};
+
+pub const DEFAULT_CONCURRENCY: usize = 500;
+
+// `bandwidth` is in bytes per second of compressed range data; it is charged after a download
+// completes, so it caps the average rate rather than every burst.
+#[derive(Debug, Clone, PartialEq)]
+pub struct UpdateOptions {
+    pub concurrency: usize,
+    pub bandwidth: Option<u64>,
+    pub requests_per_second: Option<f64>,
+    pub window: Option<ScheduleWindow>,
+}
+
+impl Default for UpdateOptions {
+    fn default() -> Self {
+        Self {
+            concurrency: DEFAULT_CONCURRENCY,
+            bandwidth: None,
+            requests_per_second: None,
+            window: None,
+        }
+    }
+}
+
+// Tokens refill at `rate` per second up to `capacity`. `reserve` takes tokens even when there
+// are not enough and returns how long the caller has to wait before using them, so work can be
+// delayed instead of blocking the loop that schedules it.
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    pub rate: f64,
+    pub capacity: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: f64, capacity: f64) -> Self {
+        Self {
+            rate,
+            capacity,
+            tokens: capacity,
+            last: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        if now > self.last {
+            self.tokens = (self.tokens + self.rate * (now - self.last).as_secs_f64()).min(self.capacity);
+            self.last = now;
+        }
+    }
+
+    pub fn reserve_at(&mut self, n: f64, now: Instant) -> Duration {
+        self.refill(now);
+        self.tokens -= n;
+        if self.tokens >= 0.0 || self.rate <= 0.0 {
+            return Duration::ZERO;
+        }
+        return Duration::from_secs_f64(-self.tokens / self.rate);
+    }
+
+    pub fn reserve(&mut self, n: f64) -> Duration {
+        return self.reserve_at(n, Instant::now());
+    }
+}
+
+// Daily local time interval during which downloads may start, e.g. `19:00-07:00`; it wraps
+// around midnight when the end is before the start.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScheduleWindow {
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+}
+
+impl FromStr for ScheduleWindow {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (start, end) = s.split_once('-').ok_or_else(|| format!("invalid window {:?}: expected HH:MM-HH:MM", s))?;
+        let time = |v: &str| NaiveTime::parse_from_str(v.trim(), "%H:%M").map_err(|e| format!("invalid window {:?}: {}", s, e));
+        Ok(Self {
+            start: time(start)?,
+            end: time(end)?,
+        })
+    }
+}
+
+impl ScheduleWindow {
+    pub fn contains(&self, t: NaiveTime) -> bool {
+        if self.start <= self.end {
+            return self.start <= t && t < self.end;
+        }
+        return t >= self.start || t < self.end;
+    }
+
+    // How long until the window opens; zero inside it.
+    pub fn wait(&self, t: NaiveTime) -> Duration {
+        if self.contains(t) {
+            return Duration::ZERO;
+        }
+        let day = 24*3600;
+        let seconds = (self.start.num_seconds_from_midnight() + day - t.num_seconds_from_midnight()) % day;
+        return Duration::from_secs(seconds as u64);
+    }
+}
+
+// Byte counts with an optional K, M or G suffix (powers of 1024), e.g. `512K` or `2M`.
+pub fn parse_bytes(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let (digits, shift) = match s.chars().last().map(|v| v.to_ascii_uppercase()) {
+        Some('K') => (&s[..s.len()-1], 10),
+        Some('M') => (&s[..s.len()-1], 20),
+        Some('G') => (&s[..s.len()-1], 30),
+        _ => (s, 0),
+    };
+    match digits.parse::<u64>() {
+        Ok(v) => v.checked_shl(shift).filter(|t| t >> shift == v).ok_or_else(|| format!("too large: {}", s)),
+        Err(e) => Err(format!("invalid size {:?}: {}", s, e)),
+    }
+}
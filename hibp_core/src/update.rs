@@ -0,0 +1,146 @@
+use std::collections::VecDeque;
+use std::fs;
+use std::io;
+use std::time::Duration;
+
+use bit_set::BitSet;
+use chrono::Local;
+use futures::stream::FuturesUnordered;
+use futures::StreamExt;
+
+use crate::db::HIBPDB;
+use crate::progress::{Progress, ProgressEvent};
+#[cfg(feature = "blocking")]
+use crate::progress::RangeCallback;
+use crate::throttle::{TokenBucket, UpdateOptions};
+use crate::{dir_list, download_range};
+
+impl HIBPDB {
+
+    #[cfg(feature = "blocking")]
+    pub fn update<F>(&self, f: F) -> io::Result<()> where F: FnMut(u32) + Send {
+        return self.update_with(&UpdateOptions::default(), &mut RangeCallback(f));
+    }
+
+    #[cfg(feature = "blocking")]
+    pub fn update_with(&self, options: &UpdateOptions, progress: &mut (dyn Progress + Send)) -> io::Result<()> {
+        return self.runtime().block_on(self.update_async(options, progress));
+    }
+
+    pub async fn update_async(&self, options: &UpdateOptions, progress: &mut (dyn Progress + Send)) -> io::Result<()> {
+        let dir_range = self.dbdir.clone()+"/range/";
+        if self.archive()?.is_none() {
+            fs::create_dir_all(dir_range.clone())?;
+        }
+
+        let limit = options.concurrency.max(1);
+        let client = reqwest::Client::new();
+
+        let mut requests = options.requests_per_second.map(|v| TokenBucket::new(v, v.max(1.0)));
+        let mut bandwidth = options.bandwidth.map(|v| TokenBucket::new(v as f64, v as f64));
+        // A request waits for a request token and for the bandwidth debt of earlier downloads.
+        let mut delay = move |bytes: u64| {
+            let mut out = Duration::ZERO;
+            if let Some(bucket) = &mut requests {
+                out = out.max(bucket.reserve(1.0));
+            }
+            if let Some(bucket) = &mut bandwidth {
+                out = out.max(bucket.reserve(bytes as f64));
+            }
+            out
+        };
+        let throttled = |range: u32, delay: Duration| {
+            let client = &client;
+            async move {
+                if !delay.is_zero() {
+                    tokio::time::sleep(delay).await;
+                }
+                download_range(client, range).await
+            }
+        };
+
+        let mut queue = FuturesUnordered::new();
+
+        let mut bs = BitSet::new();
+        if let Some(archive) = self.archive()? {
+            let archive = archive.read().unwrap();
+            for (i, entry) in archive.entries.iter().enumerate() {
+                if entry.is_present() {
+                    bs.insert(i);
+                }
+            }
+        }
+
+        let ls = match self.archive()? {
+            Some(_) => Vec::new(),
+            None => dir_list(dir_range.as_str())?,
+        };
+        for key in ls {
+            // Partial downloads and anything else that is not a range file are skipped.
+            let t = match key.get(0..5).map(|v| u32::from_str_radix(v, 16)) {
+                Some(Ok(v)) if !key.starts_with("tmp.") => v,
+                _ => continue,
+            };
+            bs.insert(t as usize);
+        }
+
+        let (start, end) = (self.prefixes.start, self.prefixes.end);
+        let missing = (start..=end).filter(|v| !bs.contains(*v as usize)).count();
+        progress.event(ProgressEvent::Started { task: "update", total: missing as u64 });
+
+        // Bytes of the last completed download, charged to the bandwidth bucket by the next
+        // request.
+        let mut received = 0u64;
+        // Failed ranges go out again before new ones, through the same window and buckets.
+        let mut retry: VecDeque<u32> = VecDeque::new();
+        let mut i = start;
+        loop {
+            while i <= end && bs.contains(i as usize) {
+                i += 1;
+            }
+            let next = retry.front().copied().or((i <= end).then_some(i));
+            if let (Some(range), true) = (next, queue.len() < limit) {
+                // Outside the window in-flight downloads finish before the loop sleeps.
+                let pause = options.window.map(|w| w.wait(Local::now().time())).unwrap_or_default();
+                if pause.is_zero() {
+                    if retry.pop_front().is_none() {
+                        i += 1;
+                    }
+                    queue.push(throttled(range, delay(received)));
+                    received = 0;
+                    continue;
+                } else if queue.is_empty() {
+                    progress.event(ProgressEvent::Paused { resume: pause });
+                    tokio::time::sleep(pause).await;
+                    continue;
+                }
+            }
+
+            if let Some(result) = queue.next().await {
+                match result {
+                    Ok(v) => {
+                        received += v.compressed.len() as u64;
+                        progress.event(ProgressEvent::Advanced { range: v.range, bytes: v.compressed.len() as u64 });
+                        self.save(v)?;
+                    }
+                    Err(err) => {
+                        progress.event(ProgressEvent::Failed { range: err.range, error: String::from("download failed, retrying") });
+                        retry.push_back(err.range);
+                    }
+                }
+            }
+
+            if i > end && retry.is_empty() && queue.is_empty() {
+                break;
+            }
+        }
+
+        if let Some(archive) = self.archive()? {
+            archive.read().unwrap().sync()?;
+        }
+
+        progress.event(ProgressEvent::Finished);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,278 @@
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::io::{ErrorKind, Read};
+
+#[cfg(feature = "build")]
+use futures::stream::FuturesOrdered;
+#[cfg(feature = "download")]
+use futures::stream::FuturesUnordered;
+#[cfg(any(feature = "build", feature = "download"))]
+use futures::StreamExt;
+use regex::Regex;
+
+use crate::db::{HIBPDB, RANGE_FILENAME};
+use crate::verify::RANGE_COUNT;
+#[cfg(feature = "download")]
+use crate::download_range;
+use crate::{dir_list, Codec};
+
+pub const SUFFIX_LEN: usize = 32-5;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RangeIssue {
+    Missing,
+    Duplicate(Vec<String>),
+    Truncated,
+    Corrupt(String),
+    Empty,
+    BadLine(usize),
+    Unsorted(usize),
+}
+
+#[derive(Debug, Default)]
+pub struct ValidationReport {
+    pub checked: u32,
+    pub bad_ranges: BTreeMap<u32, Vec<RangeIssue>>,
+}
+
+impl ValidationReport {
+    pub fn is_ok(&self) -> bool {
+        return self.bad_ranges.is_empty();
+    }
+}
+
+// Decodes with the buffered decoders so that on failure we can tell whether the whole input
+// was consumed, which is how a cut-off download shows up.
+pub fn decompress(filename: &str, compressed: &[u8]) -> Result<Vec<u8>, RangeIssue> {
+    let codec = match Codec::detect(filename, compressed) {
+        Some(v) => v,
+        None => return Err(RangeIssue::Corrupt(String::from("unsupported file type"))),
+    };
+
+    let mut plain: Vec<u8> = Vec::new();
+    let (result, remaining) = match codec {
+        Codec::Raw => return Ok(compressed.to_vec()),
+        Codec::Gz => {
+            let mut decoder = flate2::bufread::GzDecoder::new(compressed);
+            let r = decoder.read_to_end(&mut plain);
+            (r, decoder.get_ref().len())
+        }
+        Codec::Xz => {
+            let mut decoder = xz2::bufread::XzDecoder::new(compressed);
+            let r = decoder.read_to_end(&mut plain);
+            (r, decoder.get_ref().len())
+        }
+        Codec::Zstd => {
+            let mut decoder = zstd::stream::read::Decoder::with_buffer(compressed)
+                .map_err(|e| RangeIssue::Corrupt(e.to_string()))?;
+            let r = decoder.read_to_end(&mut plain);
+            (r, decoder.get_ref().len())
+        }
+    };
+
+    match result {
+        Ok(_) => Ok(plain),
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof || remaining == 0 => Err(RangeIssue::Truncated),
+        Err(e) => Err(RangeIssue::Corrupt(e.to_string())),
+    }
+}
+
+// Checks that every line of a decompressed range is `SUFFIX:COUNT`, where SUFFIX is the
+// 27 remaining hex digits of an NTLM hash, and that the suffixes are strictly ascending.
+// Line numbers in the returned issues are 1-based.
+pub fn validate_range_content(plain: &[u8]) -> Vec<RangeIssue> {
+    let mut issues: Vec<RangeIssue> = Vec::new();
+
+    let mut prev: Option<&[u8]> = None;
+    let mut lines = 0usize;
+    for (i, raw) in plain.split(|v| *v == b'\n').enumerate() {
+        let line = raw.strip_suffix(b"\r").unwrap_or(raw);
+        if line.is_empty() {
+            continue;
+        }
+        lines += 1;
+
+        let well_formed = line.len() > SUFFIX_LEN+1
+            && line[SUFFIX_LEN] == b':'
+            && line[0..SUFFIX_LEN].iter().all(|v| v.is_ascii_hexdigit())
+            && line[SUFFIX_LEN+1..].iter().all(|v| v.is_ascii_digit());
+        if !well_formed {
+            issues.push(RangeIssue::BadLine(i+1));
+            break;
+        }
+
+        let suffix = &line[0..SUFFIX_LEN];
+        if let Some(p) = prev {
+            let order = p.iter().map(u8::to_ascii_uppercase).cmp(suffix.iter().map(u8::to_ascii_uppercase));
+            if order != Ordering::Less {
+                issues.push(RangeIssue::Unsorted(i+1));
+                break;
+            }
+        }
+        prev = Some(suffix);
+    }
+
+    if lines == 0 && issues.is_empty() {
+        issues.push(RangeIssue::Empty);
+    }
+
+    return issues;
+}
+
+impl HIBPDB {
+
+    // Unlike `range_map` this never fails on a malformed corpus; every range with zero or
+    // several files is reported alongside the ones whose content is bad.
+    pub fn range_files(&self) -> io::Result<Vec<Vec<String>>> {
+        let dir_range = self.dbdir.clone()+"/range/";
+        let re = Regex::new(RANGE_FILENAME).unwrap();
+
+        let mut out: Vec<Vec<String>> = vec![Vec::new(); RANGE_COUNT];
+        let mut ls = dir_list(dir_range.as_str())?;
+        ls.sort();
+        for filename in ls {
+            if let Some(cap) = re.captures(filename.as_str()) {
+                let range = u32::from_str_radix(cap.get(1).unwrap().as_str(), 16).unwrap();
+                out[range as usize].push(filename);
+            }
+        }
+
+        return Ok(out);
+    }
+
+    #[cfg(feature = "build")]
+    async fn validate_range(&self, range: u32, files: &[String]) -> Vec<RangeIssue> {
+        match files.len() {
+            0 => return vec![RangeIssue::Missing],
+            1 => {},
+            _ => return vec![RangeIssue::Duplicate(files.to_vec())],
+        }
+
+        let filename = files[0].as_str();
+        let buff = match self.read_stored(range, filename) {
+            Ok(v) => v,
+            Err(e) => return vec![RangeIssue::Corrupt(e.to_string())],
+        };
+
+        match decompress(filename, buff.as_slice()) {
+            Ok(plain) => validate_range_content(plain.as_slice()),
+            Err(issue) => vec![issue],
+        }
+    }
+
+    #[cfg(all(feature = "build", feature = "blocking"))]
+    pub fn validate_ranges<F>(&self, f: F) -> io::Result<ValidationReport> where F: FnMut(u32) + Send {
+        return self.runtime().block_on(self.validate_ranges_async(f));
+    }
+
+    #[cfg(feature = "build")]
+    pub async fn validate_ranges_async<F>(&self, mut f: F) -> io::Result<ValidationReport> where F: FnMut(u32) + Send {
+        let files: Vec<Vec<String>> = match self.archive()? {
+            Some(archive) => {
+                let archive = archive.read().unwrap();
+                archive.entries.iter().enumerate()
+                    .map(|(i, v)| if v.is_present() { vec![v.filename(i as u32)] } else { Vec::new() })
+                    .collect()
+            }
+            None => self.range_files()?,
+        };
+
+        let mut report = ValidationReport::default();
+        let mut queue = FuturesOrdered::new();
+        let limit = 1000;
+
+        let mut wp = 0u32;
+        let mut rp = 0u32;
+        while rp < RANGE_COUNT as u32 {
+            if wp < RANGE_COUNT as u32 && queue.len() < limit {
+                queue.push_back(self.validate_range(wp, &files[wp as usize]));
+                wp += 1;
+            } else {
+                let issues = queue.next().await.unwrap();
+                if !issues.is_empty() {
+                    report.bad_ranges.insert(rp, issues);
+                }
+                report.checked += 1;
+                f(rp);
+                rp += 1;
+            }
+        }
+
+        Ok(report)
+    }
+
+    // Moves every file belonging to the given ranges out of `range/` so they can be inspected
+    // later and so `update` no longer considers those ranges present. Archived ranges are copied
+    // out of range.pack and their archive entries cleared.
+    pub fn quarantine(&self, ranges: &[u32]) -> io::Result<Vec<String>> {
+        let dir_quarantine = self.dbdir.clone()+"/quarantine/";
+        fs::create_dir_all(dir_quarantine.clone())?;
+
+        if let Some(archive) = self.archive()? {
+            let mut archive = archive.write().unwrap();
+            let mut moved: Vec<String> = Vec::new();
+            for range in ranges {
+                let filename = match archive.get(*range) {
+                    Some(entry) => entry.filename(*range),
+                    None => continue,
+                };
+                let (_, compressed) = archive.read(*range)?;
+                fs::write(dir_quarantine.clone()+filename.as_str(), compressed)?;
+                archive.remove(*range)?;
+                moved.push(filename);
+            }
+            archive.sync()?;
+            return Ok(moved);
+        }
+
+        let files = self.range_files()?;
+        let mut moved: Vec<String> = Vec::new();
+        for range in ranges {
+            for filename in &files[*range as usize] {
+                fs::rename(self.dbdir.clone()+"/range/"+filename.as_str(), dir_quarantine.clone()+filename.as_str())?;
+                moved.push(filename.clone());
+            }
+        }
+
+        return Ok(moved);
+    }
+
+    #[cfg(all(feature = "blocking", feature = "download"))]
+    pub fn repair<F>(&self, ranges: &[u32], f: F) -> io::Result<()> where F: FnMut(u32) + Send {
+        return self.runtime().block_on(self.repair_async(ranges, f));
+    }
+
+    #[cfg(feature = "download")]
+    pub async fn repair_async<F>(&self, ranges: &[u32], mut f: F) -> io::Result<()> where F: FnMut(u32) + Send {
+        self.quarantine(ranges)?;
+
+        let limit = 500;
+        let client = reqwest::Client::new();
+
+        let mut queue = FuturesUnordered::new();
+
+        let mut i = 0usize;
+        loop {
+            if i < ranges.len() && queue.len() < limit {
+                queue.push(download_range(&client, ranges[i]));
+                i += 1;
+                continue;
+            }
+
+            match queue.next().await {
+                Some(Ok(v)) => {
+                    f(v.range);
+                    self.save(v)?;
+                }
+                Some(Err(err)) => {
+                    queue.push(download_range(&client, err.range));
+                }
+                None => break,
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,215 @@
+use std::collections::BTreeMap;
+#[cfg(feature = "build")]
+use std::io;
+#[cfg(feature = "build")]
+use std::io::{BufRead, ErrorKind};
+
+#[cfg(feature = "build")]
+use futures::stream::FuturesOrdered;
+#[cfg(feature = "build")]
+use futures::StreamExt;
+#[cfg(feature = "build")]
+use rand::Rng;
+
+#[cfg(feature = "build")]
+use crate::db::HIBPDB;
+use crate::HASH;
+
+pub const RANGE_COUNT: usize = 1<<20;
+
+#[inline]
+pub fn hash_prefix(hash: &HASH) -> u32 {
+    return u32::from_be_bytes([0, hash[0], hash[1], hash[2]]) >> 4;
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct RangeStatus {
+    pub expected: Option<u64>,
+    pub actual: u64,
+    pub unsorted: u64,
+    pub duplicates: u64,
+    pub misplaced: u64,
+    pub first_bad: Option<usize>,
+    pub content_mismatch: bool,
+    pub error: Option<String>,
+}
+
+impl RangeStatus {
+    pub fn is_ok(&self) -> bool {
+        let count_ok = match self.expected {
+            Some(v) => v == self.actual,
+            None => self.error.is_none(),
+        };
+        return count_ok
+            && self.unsorted == 0
+            && self.duplicates == 0
+            && self.misplaced == 0
+            && !self.content_mismatch
+            && self.error.is_none();
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct VerifyReport {
+    pub records: u64,
+    pub spot_checked: Vec<u32>,
+    pub bad_ranges: BTreeMap<u32, RangeStatus>,
+}
+
+impl VerifyReport {
+    pub fn is_ok(&self) -> bool {
+        return self.bad_ranges.is_empty();
+    }
+}
+
+// Streams the index once, checking order and that every record falls in the slot its range
+// file says it should. `counts[r]` is the number of records range `r` is expected to hold,
+// or `None` if that range could not be read.
+pub fn verify_index(index: &[HASH], counts: Option<&[Option<u64>]>) -> VerifyReport {
+    let mut status: Vec<RangeStatus> = vec![Default::default(); RANGE_COUNT];
+
+    let mut offsets: Vec<u64> = Vec::new();
+    if let Some(counts) = counts {
+        assert_eq!(counts.len(), RANGE_COUNT);
+        offsets.reserve_exact(RANGE_COUNT+1);
+        let mut off = 0u64;
+        offsets.push(off);
+        for (r, v) in counts.iter().enumerate() {
+            status[r].expected = *v;
+            if v.is_none() {
+                status[r].error = Some(String::from("range file unreadable"));
+            }
+            off += v.unwrap_or(0);
+            offsets.push(off);
+        }
+    }
+
+    let mut slot = 0usize;
+    for (i, key) in index.iter().enumerate() {
+        let prefix = hash_prefix(key) as usize;
+        status[prefix].actual += 1;
+
+        if i > 0 {
+            let prev = &index[i-1];
+            if key == prev {
+                status[prefix].duplicates += 1;
+                status[prefix].first_bad.get_or_insert(i);
+            } else if key < prev {
+                status[prefix].unsorted += 1;
+                status[prefix].first_bad.get_or_insert(i);
+            }
+        }
+
+        if !offsets.is_empty() {
+            while slot < RANGE_COUNT && offsets[slot+1] <= i as u64 {
+                slot += 1;
+            }
+            if slot < RANGE_COUNT && slot != prefix {
+                status[slot].misplaced += 1;
+                status[slot].first_bad.get_or_insert(i);
+            }
+        }
+    }
+
+    let mut report = VerifyReport {
+        records: index.len() as u64,
+        ..Default::default()
+    };
+    for (r, v) in status.into_iter().enumerate() {
+        if !v.is_ok() {
+            report.bad_ranges.insert(r as u32, v);
+        }
+    }
+
+    return report;
+}
+
+#[cfg(feature = "build")]
+impl HIBPDB {
+
+    async fn count_range(&self, range_map: &[String], range: u32) -> io::Result<u64> {
+        let plain = self.read_range(range_map, range).await?;
+        let mut count = 0u64;
+        for line in plain.lines() {
+            if !line?.is_empty() {
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+
+    #[cfg(feature = "blocking")]
+    pub fn verify<F>(&self, spot_checks: usize, f: F) -> io::Result<VerifyReport> where F: FnMut(u32) + Send {
+        return self.runtime().block_on(self.verify_async(spot_checks, f));
+    }
+
+    pub async fn verify_async<F>(&self, spot_checks: usize, mut f: F) -> io::Result<VerifyReport> where F: FnMut(u32) + Send {
+        if self.index.is_none() {
+            return Err(io::Error::new(ErrorKind::NotFound, "index.bin not found"));
+        }
+        let coverage = self.coverage;
+        let map = self.range_map_for(coverage)?;
+
+        let mut counts: Vec<Option<u64>> = Vec::with_capacity(RANGE_COUNT);
+        let mut queue = FuturesOrdered::new();
+        let limit = 1000;
+
+        // Ranges the index was not built from must be empty in it.
+        let mut wp = coverage.start;
+        let mut rp = 0u32;
+        while rp < RANGE_COUNT as u32 {
+            if wp <= coverage.end && queue.len() < limit {
+                queue.push_back(self.count_range(&map, wp));
+                wp += 1;
+            } else if !coverage.contains(rp) {
+                counts.push(Some(0));
+                f(rp);
+                rp += 1;
+            } else {
+                counts.push(queue.next().await.unwrap().ok());
+                f(rp);
+                rp += 1;
+            }
+        }
+
+        let index = self.index();
+        let mut report = verify_index(index, Some(counts.as_slice()));
+
+        let mut offsets: Vec<usize> = Vec::with_capacity(RANGE_COUNT+1);
+        let mut off = 0usize;
+        offsets.push(off);
+        for v in &counts {
+            off += v.unwrap_or(0) as usize;
+            offsets.push(off);
+        }
+
+        // thread_rng is not Send, so it is dropped before the first await below.
+        let ranges: Vec<u32> = {
+            let mut rng = rand::thread_rng();
+            (0..spot_checks).map(|_| rng.gen_range(coverage.start..=coverage.end)).collect()
+        };
+        for range in ranges {
+            report.spot_checked.push(range);
+            if report.bad_ranges.contains_key(&range) {
+                continue;
+            }
+
+            let (lo, hi) = (offsets[range as usize], offsets[range as usize+1]);
+            let matches = match self.extract_range(&map, range).await {
+                Ok(buff) => hi <= index.len() && buff.chunks_exact(16).eq(index[lo..hi].iter().map(|v| v.as_slice())),
+                Err(_) => false,
+            };
+            if !matches {
+                let status = RangeStatus {
+                    expected: counts[range as usize],
+                    actual: (hi-lo) as u64,
+                    content_mismatch: true,
+                    ..Default::default()
+                };
+                report.bad_ranges.insert(range, status);
+            }
+        }
+
+        Ok(report)
+    }
+}
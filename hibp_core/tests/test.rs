@@ -4,7 +4,52 @@ use std::{fs};
 const DIR_TESTS_DATA: &str = "tests/data";
 
 
-use hibp_core::{download_range};
+use futures::StreamExt;
+use rand::{Rng, SeedableRng};
+use hibp_core::{compress_gz, download_range, encode_to_utf16le, hash_password_batch, Codec, HashAndPassword, HashRange, RandomItemGenerator, HASH, HASH_to_hex};
+use hibp_core::db::{SearchStrategy, HIBPDB};
+use hibp_core::compact::{CompactIndex, CompactIndexWriter, COMPACT_INDEX, MAX_SUFFIX_BYTES};
+use hibp_core::eytzinger::{eytzinger_layout, eytzinger_rank, EytzingerSearch};
+use hibp_core::filter::{BinaryFuse8, FilterWriter, FILTER_INDEX};
+use hibp_core::coverage::{Lookup, PrefixRange};
+use hibp_core::decode::{DecodePolicy, DecodeStats, PasswordDecoder};
+use hibp_core::gc::GcOptions;
+use hibp_core::learned::{LearnedModelWriter, LEARNED_MODEL};
+use hibp_core::md4_fast::{ntlm, ntlm_batch_with, ntlm_utf8, Isa, NtlmHasher, NTLM_MAX_CHARS};
+use hibp_core::progress::{Progress, ProgressEvent, ProgressStats, RangeCallback};
+use hibp_core::reader::Reader;
+use hibp_core::residency::{AccessPattern, IndexOptions};
+use hibp_core::rules::{Rule, RuleSet};
+use hibp_core::shard::{split_prefixes, ShardManifest, SHARD_MANIFEST};
+use hibp_core::throttle::{parse_bytes, ScheduleWindow, TokenBucket, UpdateOptions};
+use hibp_core::validate::{decompress, validate_range_content, RangeIssue};
+use hibp_core::verify::{hash_prefix, verify_index, RANGE_COUNT};
+
+fn scratch_dbdir(name: &str) -> String {
+    let path = std::env::temp_dir().join(format!("hibp_{}_{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&path);
+    fs::create_dir_all(path.join("range")).unwrap();
+    String::from(path.to_str().unwrap())
+}
+
+// Saves every range in `prefixes` with two records, the second ending in the range itself.
+// Ranges from `empty_from` on are saved without records.
+fn save_ranges(db: &HIBPDB, prefixes: PrefixRange, empty_from: Option<u32>) {
+    for range in prefixes.start..=prefixes.end {
+        let content = match empty_from {
+            Some(v) if range >= v => String::new(),
+            _ => format!("{:027X}:1\r\n{:027X}:2\r\n", 1, range),
+        };
+        db.save(HashRange{range, etag: 1, timestamp: 1_700_000_000, codec: Codec::Gz, compressed: compress_gz(content.as_bytes()).unwrap()}).unwrap();
+    }
+}
+
+fn hash_with_prefix(prefix: u32, low: u64) -> HASH {
+    let mut hash: HASH = [0u8; 16];
+    hash[8..16].copy_from_slice(&low.to_be_bytes());
+    hash[0..4].copy_from_slice(&(prefix << 12).to_be_bytes());
+    hash
+}
 
 #[test]
 fn test_test_data_directory() {
@@ -15,6 +60,7 @@ fn test_test_data_directory() {
 }
 
 #[test]
+#[ignore = "downloads from api.pwnedpasswords.com"]
 fn test_arbitrary_code_snippet() {
 
     let rt = tokio::runtime::Builder::new_current_thread()
@@ -30,6 +76,753 @@ fn test_arbitrary_code_snippet() {
     assert!(result.is_ok());
 }
 
+#[test]
+fn test_verify_index() {
+    let mut index: Vec<HASH> = Vec::new();
+    let mut counts: Vec<Option<u64>> = vec![Some(0); RANGE_COUNT];
+    for prefix in [0u32, 1, 0xABCDE, 0xFFFFF] {
+        for low in 0..3 {
+            index.push(hash_with_prefix(prefix, low));
+        }
+        counts[prefix as usize] = Some(3);
+    }
+    assert_eq!(hash_prefix(&index[6]), 0xABCDE);
+
+    let report = verify_index(&index, Some(&counts));
+    assert!(report.is_ok());
+    assert_eq!(report.records, 12);
+
+    let mut broken = index.clone();
+    broken[7] = broken[6];
+    counts[0x12345] = Some(1);
+    let report = verify_index(&broken, Some(&counts));
+    assert_eq!(report.bad_ranges.keys().copied().collect::<Vec<u32>>(), vec![0x12345, 0xABCDE]);
+    assert_eq!(report.bad_ranges[&0xABCDE].duplicates, 1);
+    assert_eq!(report.bad_ranges[&0xABCDE].first_bad, Some(7));
+    assert_eq!(report.bad_ranges[&0x12345].actual, 0);
+}
+
+#[test]
+fn test_validate_range_content() {
+    let good = b"0005AD76BD555C1D6D771DE417A:4\r\n000DD7F2A1C68A35673713783CA:12\r\n";
+    assert!(validate_range_content(good).is_empty());
+
+    let unsorted = b"000DD7F2A1C68A35673713783CA:12\r\n0005AD76BD555C1D6D771DE417A:4\r\n";
+    assert_eq!(validate_range_content(unsorted), vec![RangeIssue::Unsorted(2)]);
+
+    let bad_line = b"0005AD76BD555C1D6D771DE417A:4\r\n000DD7F2A1C68A3567371378:12\r\n";
+    assert_eq!(validate_range_content(bad_line), vec![RangeIssue::BadLine(2)]);
+
+    assert_eq!(validate_range_content(b""), vec![RangeIssue::Empty]);
+
+    let compressed = compress_gz(good).unwrap();
+    assert_eq!(decompress("00000_0000000000000000.gz", &compressed).unwrap(), good.to_vec());
+    let truncated = &compressed[0..compressed.len()/2];
+    assert_eq!(decompress("00000_0000000000000000.gz", truncated), Err(RangeIssue::Truncated));
+}
+
+#[test]
+fn test_gc() {
+    let dbdir = scratch_dbdir("gc");
+    let db = HIBPDB::new(dbdir.clone()).unwrap();
+
+    for (range, etag, timestamp) in [(0u32, 2u64, 1_700_000_000i64), (0, 1, 1_710_000_000), (1, 3, 1_700_000_000)] {
+        db.save(HashRange{range, etag, timestamp, codec: Codec::Gz, compressed: compress_gz(b"").unwrap()}).unwrap();
+    }
+    fs::write(dbdir.clone()+"/range/tmp.00002_0000000000000004.gz", b"").unwrap();
+
+    let report = db.gc(&GcOptions{dry_run: true, archive: false}).unwrap();
+    assert_eq!(report.kept, 2);
+    assert_eq!(report.superseded, vec![String::from("00000_0000000000000002.gz")]);
+    assert_eq!(report.temp, vec![String::from("tmp.00002_0000000000000004.gz")]);
+    assert_eq!(fs::read_dir(dbdir.clone()+"/range").unwrap().count(), 4);
+
+    db.gc(&GcOptions{dry_run: false, archive: true}).unwrap();
+    let files = db.range_files().unwrap();
+    assert_eq!(files[0], vec![String::from("00000_0000000000000001.gz")]);
+    assert_eq!(fs::read_dir(dbdir.clone()+"/range").unwrap().count(), 2);
+    assert!(fs::metadata(dbdir.clone()+"/archive/00000_0000000000000002.gz").is_ok());
+
+    fs::remove_dir_all(dbdir).unwrap();
+}
+
+#[test]
+fn test_codec_recompress() {
+    let plain = b"0005AD76BD555C1D6D771DE417A:4\r\n000DD7F2A1C68A35673713783CA:12\r\n";
+    for codec in Codec::ALL {
+        let compressed = codec.compress(plain).unwrap();
+        assert_eq!(Codec::from_magic(&compressed), Some(codec));
+        assert_eq!(Codec::detect("00000_0000000000000000", &compressed), Some(codec));
+        assert_eq!(codec.extract(&compressed).unwrap(), plain.to_vec());
+    }
+
+    let dbdir = scratch_dbdir("codec");
+    let mut db = HIBPDB::new(dbdir.clone()).unwrap();
+    db.codec = Codec::Xz;
+    db.save(HashRange{range: 0, etag: 1, timestamp: 1_700_000_000, codec: Codec::Gz, compressed: compress_gz(plain).unwrap()}).unwrap();
+    db.save(HashRange{range: 1, etag: 2, timestamp: 1_700_000_000, codec: Codec::Gz, compressed: compress_gz(plain).unwrap()}).unwrap();
+    assert_eq!(db.range_files().unwrap()[0], vec![String::from("00000_0000000000000001.xz")]);
+
+    db.recompress(Codec::Zstd, |_| {}).unwrap();
+    let files = db.range_files().unwrap();
+    assert_eq!(files[0], vec![String::from("00000_0000000000000001.zst")]);
+    assert_eq!(files[1], vec![String::from("00001_0000000000000002.zst")]);
+    let stored = fs::read(dbdir.clone()+"/range/00001_0000000000000002.zst").unwrap();
+    assert_eq!(decompress(&files[1][0], &stored).unwrap(), plain.to_vec());
+
+    fs::remove_dir_all(dbdir).unwrap();
+}
+
+#[test]
+fn test_archive_pack_unpack() {
+    let plain = b"0005AD76BD555C1D6D771DE417A:4\r\n000DD7F2A1C68A35673713783CA:12\r\n";
+    let dbdir = scratch_dbdir("archive");
+    let mut db = HIBPDB::new(dbdir.clone()).unwrap();
+    db.save(HashRange{range: 0, etag: 1, timestamp: 1_700_000_000, codec: Codec::Gz, compressed: compress_gz(plain).unwrap()}).unwrap();
+    db.save(HashRange{range: 0xFFFFF, etag: 2, timestamp: 1_710_000_000, codec: Codec::Gz, compressed: compress_gz(plain).unwrap()}).unwrap();
+
+    db.pack(|_| {}).unwrap();
+    fs::remove_dir_all(dbdir.clone()+"/range").unwrap();
+
+    let mut db = HIBPDB::new(dbdir.clone()).unwrap();
+    {
+        let archive = db.archive().unwrap().unwrap().read().unwrap();
+        assert!(archive.get(1).is_none());
+        let entry = archive.get(0xFFFFF).unwrap();
+        assert_eq!((entry.etag, entry.timestamp, entry.codec), (2, 1_710_000_000, Some(Codec::Gz)));
+        let (codec, compressed) = archive.read(0xFFFFF).unwrap();
+        assert_eq!(codec.extract(&compressed).unwrap(), plain.to_vec());
+    }
+
+    db.codec = Codec::Zstd;
+    db.save(HashRange{range: 1, etag: 3, timestamp: 1_720_000_000, codec: Codec::Gz, compressed: compress_gz(plain).unwrap()}).unwrap();
+    let report = db.validate_ranges(|_| {}).unwrap();
+    assert_eq!(report.bad_ranges.len(), (1<<20) - 3);
+    assert!(!report.bad_ranges.contains_key(&1));
+
+    // Packing an archive-only db keeps every range and replaces the archive in place.
+    let previous = fs::read(dbdir.clone()+"/range.pack").unwrap();
+    db.pack(|_| {}).unwrap();
+    let report = db.validate_ranges(|_| {}).unwrap();
+    assert_eq!(report.bad_ranges.len(), (1<<20) - 3);
+    assert!(fs::metadata(dbdir.clone()+"/range.pack.tmp").is_err());
+    assert!(fs::metadata(dbdir.clone()+"/range.idx.tmp").is_err());
+
+    // Quarantine copies an archived range out of the archive and clears its entry.
+    assert_eq!(db.quarantine(&[0, 2]).unwrap(), vec![String::from("00000_0000000000000001.gz")]);
+    assert!(fs::metadata(dbdir.clone()+"/quarantine/00000_0000000000000001.gz").is_ok());
+    let db = HIBPDB::new(dbdir.clone()).unwrap();
+    assert!(db.archive().unwrap().unwrap().read().unwrap().get(0).is_none());
+    let report = db.validate_ranges(|_| {}).unwrap();
+    assert_eq!(report.bad_ranges.get(&0), Some(&vec![RangeIssue::Missing]));
+
+    db.unpack(|_| {}).unwrap();
+    let files = db.range_files().unwrap();
+    assert_eq!(files[1], vec![String::from("00001_0000000000000003.zst")]);
+    assert_eq!(files[0xFFFFF], vec![String::from("FFFFF_0000000000000002.gz")]);
+
+    // Data left from the previous pack, as after a crash between its two renames, is refused.
+    fs::write(dbdir.clone()+"/range.pack", previous).unwrap();
+    let err = HIBPDB::new(dbdir.clone()).unwrap().archive().err().unwrap();
+    assert_eq!((err.kind(), err.to_string().as_str()), (std::io::ErrorKind::InvalidData, "range.pack and range.idx are from different packs"));
+
+    fs::remove_dir_all(dbdir).unwrap();
+}
+
+#[test]
+fn test_compact_index() {
+    let dbdir = scratch_dbdir("compact");
+
+    let mut index: Vec<HASH> = Vec::new();
+    for prefix in [0u32, 0x12345, 0xFFFFF] {
+        for low in [5u64, 9, 1<<40] {
+            index.push(hash_with_prefix(prefix, low));
+        }
+    }
+    let mut writer = CompactIndexWriter::create((dbdir.clone()+"/"+COMPACT_INDEX).as_str(), MAX_SUFFIX_BYTES).unwrap();
+    for chunk in index.chunks(3) {
+        writer.push_range(hash_prefix(&chunk[0]), chunk.concat().as_slice()).unwrap();
+    }
+    writer.finish().unwrap();
+
+    let db = HIBPDB::new(dbdir.clone()).unwrap();
+    assert!(db.index.is_none());
+    assert_eq!(db.len(), 9);
+    assert_eq!(db.compact.as_ref().unwrap().false_positive_rate(), 0.0);
+    for (i, key) in index.iter().enumerate() {
+        assert_eq!(db.find(key).unwrap(), Ok(i));
+    }
+    assert_eq!(db.find(&hash_with_prefix(0x12345, 7)).unwrap(), Err(4));
+    assert_eq!(db.find(&hash_with_prefix(0x12346, 0)).unwrap(), Err(6));
+
+    let mut writer = CompactIndexWriter::create((dbdir.clone()+"/"+COMPACT_INDEX).as_str(), 4).unwrap();
+    writer.push_range(0x12345, index[3..6].concat().as_slice()).unwrap();
+    writer.finish().unwrap();
+    let compact = CompactIndex::open((dbdir.clone()+"/"+COMPACT_INDEX).as_str(), &IndexOptions::default()).unwrap();
+    let mut miss = index[5];
+    miss[4] = 0xFF;
+    assert_eq!(compact.find(&miss), Err(3));
+    assert!(compact.find(&hash_with_prefix(0x12345, 7)).is_ok());
+    assert!(compact.false_positive_rate() > 0.0);
+    drop(compact);
+
+    // Corrupt headers and offset tables are rejected at open rather than during lookups.
+    let pathname = dbdir.clone()+"/"+COMPACT_INDEX;
+    let raw = fs::read(&pathname).unwrap();
+    let mut bad = raw.clone();
+    bad[12] = 0;
+    fs::write(&pathname, &bad).unwrap();
+    assert_eq!(CompactIndex::open(pathname.as_str(), &IndexOptions::default()).err().unwrap().kind(), std::io::ErrorKind::InvalidData);
+    let mut bad = raw.clone();
+    bad[24+8*0x12345..24+8*0x12346].copy_from_slice(&5u64.to_le_bytes());
+    fs::write(&pathname, &bad).unwrap();
+    assert_eq!(CompactIndex::open(pathname.as_str(), &IndexOptions::default()).err().unwrap().kind(), std::io::ErrorKind::InvalidData);
+
+    fs::remove_dir_all(dbdir).unwrap();
+}
+
+#[test]
+fn test_filter_index() {
+    let mut rng: RandomItemGenerator<HASH> = RandomItemGenerator::new(1000);
+    let mut index: Vec<HASH> = (0..200_000).map(|_| *rng.next_item()).collect();
+    index.sort();
+
+    let dbdir = scratch_dbdir("filter");
+    let mut writer = FilterWriter::create((dbdir.clone()+"/"+FILTER_INDEX).as_str(), 4, 42).unwrap();
+    let mut start = 0;
+    while start < index.len() {
+        let range = hash_prefix(&index[start]);
+        let end = start + index[start..].iter().take_while(|v| hash_prefix(v) == range).count();
+        writer.push_range(range, index[start..end].concat().as_slice()).unwrap();
+        start = end;
+    }
+    writer.finish().unwrap();
+
+    let db = HIBPDB::new(dbdir.clone()).unwrap();
+    let filter = db.filter.as_ref().unwrap();
+    assert_eq!((filter.len(), filter.bucket_bits, filter.snapshot), (200_000, 4, 42));
+    assert!(index.iter().all(|v| db.contains(v).unwrap()));
+
+    let trials = 200_000;
+    let false_positives = (0..trials).filter(|_| db.contains(rng.next_item()).unwrap()).count();
+    assert!((false_positives as f64 / trials as f64) < 2.0*BinaryFuse8::false_positive_rate());
+    assert_eq!(db.len(), 200_000);
+    assert_eq!(db.find(&index[0]).err().unwrap().kind(), std::io::ErrorKind::Unsupported);
+    drop(db);
+
+    let pathname = dbdir.clone()+"/"+FILTER_INDEX;
+    let mut raw = fs::read(&pathname).unwrap();
+    raw[12..16].copy_from_slice(&64u32.to_le_bytes());
+    fs::write(&pathname, &raw).unwrap();
+    assert_eq!(HIBPDB::new(dbdir.clone()).err().unwrap().kind(), std::io::ErrorKind::InvalidData);
+
+    let (empty, fingerprints) = BinaryFuse8::build(&[]).unwrap();
+    assert_eq!(fingerprints.len(), empty.array_length as usize);
+
+    // 12371 keys round up to whole segments with almost no slack; these keys peel with no seed.
+    let mut rng = rand::rngs::StdRng::seed_from_u64(12);
+    let keys: Vec<u64> = (0..12371).map(|_| rng.gen()).collect();
+    let (filter, fingerprints) = BinaryFuse8::build(&keys).unwrap();
+    assert!(keys.iter().all(|v| filter.contains(&fingerprints, *v)));
+
+    fs::remove_dir_all(dbdir).unwrap();
+}
+
+#[test]
+fn test_eytzinger_search() {
+    let mut rng: RandomItemGenerator<HASH> = RandomItemGenerator::new(1000);
+    for n in [0usize, 1, 2, 7, 8, 1000] {
+        let mut sorted: Vec<HASH> = (0..n).map(|_| *rng.next_item()).collect();
+        sorted.sort();
+        let mut layout: Vec<HASH> = vec![[0u8; 16]; n+1];
+        eytzinger_layout(&sorted, &mut layout);
+
+        for (i, key) in sorted.iter().enumerate() {
+            let k = layout.eytzinger_search(key).unwrap();
+            assert_eq!(&layout[k], key);
+            assert_eq!(eytzinger_rank(k, n), i);
+        }
+        for _ in 0..100 {
+            let key = rng.next_item();
+            let lower_bound = sorted.partition_point(|v| v < key);
+            match layout.eytzinger_search(key) {
+                Ok(_) => panic!("unexpected hit"),
+                Err(0) => assert_eq!(lower_bound, n),
+                Err(k) => assert_eq!(layout[k], sorted[lower_bound]),
+            }
+            assert_eq!(eytzinger_rank(layout.eytzinger_search(key).unwrap_err(), n), lower_bound);
+        }
+    }
+
+    let dbdir = scratch_dbdir("eytzinger");
+    let mut sorted: Vec<HASH> = (0..100).map(|_| *rng.next_item()).collect();
+    sorted.sort();
+    fs::write(dbdir.clone()+"/index.bin", sorted.concat()).unwrap();
+    let mut db = HIBPDB::new(dbdir.clone()).unwrap();
+    assert_eq!(db.strategy(), SearchStrategy::Interpolation);
+    assert_eq!(db.set_strategy(SearchStrategy::Eytzinger).err().unwrap().kind(), std::io::ErrorKind::NotFound);
+    db.construct_eytzinger().unwrap();
+    assert_eq!(db.eytzinger().len(), 101);
+    assert!(sorted.iter().all(|v| db.eytzinger().eytzinger_search(v).is_ok()));
+
+    // Opening picks the Eytzinger layout up, and positions stay those of index.bin.
+    let db = HIBPDB::new(dbdir.clone()).unwrap();
+    assert_eq!(db.strategy(), SearchStrategy::Eytzinger);
+    for (i, key) in sorted.iter().enumerate() {
+        assert_eq!(db.find(key).unwrap(), Ok(i));
+    }
+    let key = rng.next_item();
+    assert_eq!(db.find(key).unwrap(), sorted.binary_search(key));
+    drop(db);
+
+    fs::write(dbdir.clone()+"/index.bin", sorted[1..].concat()).unwrap();
+    let mut db = HIBPDB::new(dbdir.clone()).unwrap();
+    assert_eq!(db.strategy(), SearchStrategy::Interpolation);
+    assert_eq!(db.set_strategy(SearchStrategy::Eytzinger).err().unwrap().kind(), std::io::ErrorKind::InvalidData);
+    fs::remove_dir_all(dbdir).unwrap();
+}
+
+#[test]
+fn test_learned_search() {
+    let dbdir = scratch_dbdir("learned");
+    let mut rng: RandomItemGenerator<HASH> = RandomItemGenerator::new(1000);
+
+    let mut index: Vec<HASH> = (0..1000).map(|_| *rng.next_item()).collect();
+    for low in [1u64, 2, 3, 1<<63] {
+        index.push(hash_with_prefix(0xABCDE, low));
+    }
+    index.sort();
+    fs::write(dbdir.clone()+"/index.bin", index.concat()).unwrap();
+
+    let mut writer = LearnedModelWriter::create((dbdir.clone()+"/"+LEARNED_MODEL).as_str()).unwrap();
+    for chunk in index.chunk_by(|a, b| hash_prefix(a) == hash_prefix(b)) {
+        writer.push_range(hash_prefix(&chunk[0]), chunk.concat().as_slice()).unwrap();
+    }
+    writer.finish().unwrap();
+
+    let db = HIBPDB::new(dbdir.clone()).unwrap();
+    assert_eq!(db.learned.as_ref().unwrap().records, index.len() as u64);
+    assert_eq!(db.strategy(), SearchStrategy::Learned);
+    for (i, key) in index.clone().iter().enumerate() {
+        assert_eq!(db.find(key).unwrap(), Ok(i));
+    }
+    for _ in 0..1000 {
+        let key = rng.next_item();
+        assert_eq!(db.find(key).unwrap(), index.binary_search(key));
+    }
+    assert_eq!(db.find(&hash_with_prefix(0xABCDE, 4)).unwrap(), index.binary_search(&hash_with_prefix(0xABCDE, 4)));
+    fs::remove_dir_all(dbdir).unwrap();
+}
+
+#[test]
+fn test_index_options() {
+    let dbdir = scratch_dbdir("residency");
+    let mut rng: RandomItemGenerator<HASH> = RandomItemGenerator::new(1000);
+    let mut index: Vec<HASH> = (0..100000).map(|_| *rng.next_item()).collect();
+    index.sort();
+    fs::write(dbdir.clone()+"/index.bin", index.concat()).unwrap();
+
+    let options = IndexOptions {
+        access: AccessPattern::Random,
+        populate: true,
+        preload: true,
+        huge_pages: true,
+        ..Default::default()
+    };
+    let db = HIBPDB::open(dbdir.clone(), options).unwrap();
+    assert_eq!(db.options, options);
+    assert_eq!(db.find(&index[500]).unwrap(), Ok(500));
+
+    let mut progress: Vec<u32> = Vec::new();
+    db.preload(|v| progress.push(v));
+    assert_eq!(progress.len(), 2);
+    assert!(progress.is_sorted());
+    assert_eq!(*progress.last().unwrap(), hash_prefix(index.last().unwrap()));
+    assert_eq!("sequential".parse::<AccessPattern>(), Ok(AccessPattern::Sequential));
+    drop(db);
+
+    // Less than one record resident reports nothing.
+    fs::write(dbdir.clone()+"/index.bin", &index[0][0..8]).unwrap();
+    let mut progress: Vec<u32> = Vec::new();
+    HIBPDB::new(dbdir.clone()).unwrap().preload(|v| progress.push(v));
+    assert!(progress.is_empty());
+    fs::remove_dir_all(dbdir).unwrap();
+}
+
+#[test]
+fn test_index_in_memory() {
+    let dbdir = scratch_dbdir("inmemory");
+    let mut rng: RandomItemGenerator<HASH> = RandomItemGenerator::new(1000);
+    let mut index: Vec<HASH> = (0..1000).map(|_| *rng.next_item()).collect();
+    index.sort();
+    fs::write(dbdir.clone()+"/index.bin", index.concat()).unwrap();
+
+    let mut options = IndexOptions {
+        in_memory: true,
+        huge_pages: true,
+        memory_budget: Some(16*999),
+        ..Default::default()
+    };
+    let err = HIBPDB::open(dbdir.clone(), options).err().unwrap();
+    assert_eq!(err.kind(), std::io::ErrorKind::OutOfMemory);
+
+    options.memory_budget = Some(16*1000);
+    let db = HIBPDB::open(dbdir.clone(), options).unwrap();
+    fs::remove_file(dbdir.clone()+"/index.bin").unwrap();
+    fs::write(dbdir.clone()+"/index.bin", [0u8; 16]).unwrap();
+    assert_eq!(db.len(), 1000);
+    assert_eq!(db.index(), index.as_slice());
+    assert_eq!(db.find(&index[500]).unwrap(), Ok(500));
+    drop(db);
+
+    // The budget covers index.bin and index.eytzinger together.
+    fs::write(dbdir.clone()+"/index.bin", index.concat()).unwrap();
+    HIBPDB::new(dbdir.clone()).unwrap().construct_eytzinger().unwrap();
+    options.memory_budget = Some(16*2000);
+    let err = HIBPDB::open(dbdir.clone(), options).err().unwrap();
+    assert_eq!(err.kind(), std::io::ErrorKind::OutOfMemory);
+    options.memory_budget = Some(16*2001);
+    assert!(HIBPDB::open(dbdir.clone(), options).is_ok());
+
+    // A compact-only db is loaded into memory and budgeted the same way.
+    fs::remove_file(dbdir.clone()+"/index.bin").unwrap();
+    fs::remove_file(dbdir.clone()+"/index.eytzinger").unwrap();
+    let pathname = dbdir.clone()+"/"+COMPACT_INDEX;
+    let mut writer = CompactIndexWriter::create(pathname.as_str(), 8).unwrap();
+    for chunk in index.chunk_by(|a, b| hash_prefix(a) == hash_prefix(b)) {
+        writer.push_range(hash_prefix(&chunk[0]), chunk.concat().as_slice()).unwrap();
+    }
+    writer.finish().unwrap();
+    let len = fs::metadata(&pathname).unwrap().len();
+    options.memory_budget = Some(len-1);
+    assert_eq!(HIBPDB::open(dbdir.clone(), options).err().unwrap().kind(), std::io::ErrorKind::OutOfMemory);
+    options.memory_budget = Some(len);
+    let db = HIBPDB::open(dbdir.clone(), options).unwrap();
+    fs::remove_file(&pathname).unwrap();
+    fs::write(&pathname, [0u8; 16]).unwrap();
+    assert_eq!(db.find(&index[500]).unwrap(), Ok(500));
+    fs::remove_dir_all(dbdir).unwrap();
+}
+
+#[test]
+fn test_md4_fast() {
+    use md4::{Digest, Md4};
+
+    let reference = |data: &[u8]| -> HASH { Md4::digest(data).into() };
+    let bytes: Vec<u8> = (0..200u32).map(|v| (v*7) as u8).collect();
+    for n in 0..bytes.len() {
+        assert_eq!(hibp_core::md4_fast::md4(&bytes[..n]), reference(&bytes[..n]));
+    }
+
+    let mut passwords: Vec<String> = (0..40).map(|n| "p".repeat(n)).collect();
+    passwords.push("Pässwört".into());
+    passwords.push("密码".repeat(NTLM_MAX_CHARS/2));
+    passwords.push("😀".repeat(NTLM_MAX_CHARS/2));
+    passwords.push("😀".repeat(NTLM_MAX_CHARS/2 + 1));
+    let expected: Vec<HASH> = passwords.iter().map(|v| reference(encode_to_utf16le(v).as_slice())).collect();
+    for (password, hash) in passwords.iter().zip(&expected) {
+        assert_eq!(&ntlm(password), hash);
+    }
+
+    let refs: Vec<&str> = passwords.iter().map(|v| v.as_str()).collect();
+    for isa in [Isa::Scalar, Isa::Sse2, Isa::Avx2, Isa::Avx512] {
+        if !isa.is_supported() {
+            continue;
+        }
+        let mut out = vec![[0u8; 16]; refs.len()];
+        ntlm_batch_with(isa, refs.as_slice(), out.as_mut_slice());
+        assert_eq!(out, expected, "{:?}", isa);
+    }
+}
+
+#[test]
+fn test_fused_password_hashing() {
+    use md4::{Digest, Md4};
+
+    let reference = |password: &str| -> HASH { Md4::digest(encode_to_utf16le(password)).into() };
+    let mut hasher = NtlmHasher::default();
+    for password in ["", "a", &"x".repeat(31), &"x".repeat(32), &"é😀".repeat(50)] {
+        assert_eq!(ntlm_utf8(password.as_bytes()), Ok(reference(password)));
+
+        for part in password.split_inclusive('😀') {
+            hasher.update(part);
+        }
+        assert_eq!(hasher.finalize_reset(), reference(password));
+    }
+    assert!(ntlm_utf8(b"\xff").is_err());
+
+    let mut passwords: Vec<Vec<u8>> = (0..40).map(|n| "p".repeat(n).into_bytes()).collect();
+    passwords[3] = b"\xc3\x28".to_vec();
+    let mut items: Vec<HashAndPassword> = passwords.iter().map(|v| HashAndPassword {
+        hash: [0u8; 16],
+        password: v.clone(),
+    }).collect();
+    let mut valid = vec![false; items.len()];
+    hash_password_batch(items.as_mut_slice(), valid.as_mut_slice());
+    for (i, hp) in items.iter().enumerate() {
+        match std::str::from_utf8(&passwords[i]) {
+            Ok(v) => assert!(valid[i] && hp.hash == reference(v)),
+            Err(_) => assert!(!valid[i] && hp.hash == [0u8; 16]),
+        }
+    }
+}
+
+#[test]
+fn test_password_decoder() {
+    let lines: [&[u8]; 5] = [b"caf\xc3\xa9", b"caf\xe9", b"\x80uro", b"$HEX[636166e9]", b"$HEX[zz]"];
+    let decode = |policy: DecodePolicy, hex: bool| {
+        let mut decoder = PasswordDecoder::new(policy, hex);
+        let mut out: Vec<u8> = Vec::new();
+        let decoded: Vec<Option<String>> = lines.iter()
+            .map(|v| decoder.decode(v, &mut out).then(|| String::from_utf8(out.clone()).unwrap()))
+            .collect();
+        (decoded, decoder.stats)
+    };
+
+    let (decoded, stats) = decode(DecodePolicy::Strict, false);
+    assert_eq!(decoded, [Some("café".into()), None, None, Some("$HEX[636166e9]".into()), Some("$HEX[zz]".into())]);
+    assert_eq!(stats, DecodeStats { utf8: 3, rejected: 2, ..Default::default() });
+
+    let (decoded, stats) = decode(DecodePolicy::Latin1, true);
+    assert_eq!(decoded[1], Some("café".into()));
+    assert_eq!(decoded[2], Some("\u{80}uro".into()));
+    assert_eq!(decoded[3], Some("café".into()));
+    assert_eq!(stats, DecodeStats { utf8: 2, hex: 1, latin1: 3, ..Default::default() });
+
+    let (decoded, _) = decode(DecodePolicy::Cp1252, true);
+    assert_eq!(decoded[2], Some("€uro".into()));
+
+    let (decoded, stats) = decode(DecodePolicy::Lossy, false);
+    assert_eq!(decoded[1], Some("caf\u{FFFD}".into()));
+    assert_eq!(stats.lossy, 2);
+    assert_eq!("cp1252".parse::<DecodePolicy>(), Ok(DecodePolicy::Cp1252));
+}
+
+#[test]
+fn test_rules() {
+    let apply = |rule: &str, word: &str| rule.parse::<Rule>().unwrap().apply(word);
+    assert_eq!(apply("c", "pASSWORD"), Some("Password".into()));
+    assert_eq!(apply("C", "password"), Some("pASSWORD".into()));
+    assert_eq!(apply("T0 r", "abc"), Some("cbA".into()));
+    assert_eq!(apply("$1$2$3 ^!", "abc"), Some("!abc123".into()));
+    assert_eq!(apply("sa@ so0", "password"), Some("p@ssw0rd".into()));
+    assert_eq!(apply("'4 ]", "password"), Some("pas".into()));
+    assert_eq!(apply("?d", "password2024"), Some("password".into()));
+    assert_eq!(apply("?y", "summer1999"), Some("summer".into()));
+    assert_eq!(apply("?y", "summer123"), None);
+    assert_eq!(apply("DA", "short"), None);
+    assert!("x".parse::<Rule>().is_err());
+    assert!("$".parse::<Rule>().is_err());
+
+    let rules = RuleSet::parse("# comment\n:\nl\nu\n?d\n").unwrap();
+    let candidates: Vec<String> = rules.candidates("abc1").into_iter().map(|(_, v)| v).collect();
+    assert_eq!(candidates, ["abc1", "ABC1", "abc"]);
+
+    let dbdir = scratch_dbdir("rules");
+    let mut index: Vec<HASH> = ["Summer", "p@ssw0rd"].iter().map(|v| ntlm(v)).collect();
+    index.push([0u8; 16]);
+    index.push([0xFFu8; 16]);
+    index.sort();
+    fs::write(dbdir.clone()+"/index.bin", index.concat()).unwrap();
+
+    let db = HIBPDB::new(dbdir.clone()).unwrap();
+    let matches = db.check_variants(&RuleSet::default(), "summer2021");
+    assert_eq!(matches.len(), 1);
+    assert_eq!((matches[0].rule.as_str(), matches[0].candidate.as_str()), ("c ?d", "Summer"));
+    let matches = db.check_variants(&RuleSet::default(), "Password");
+    assert_eq!(matches.iter().map(|v| v.candidate.as_str()).collect::<Vec<_>>(), ["p@ssw0rd"]);
+    assert!(db.check_variants(&RuleSet::default(), "unrelated").is_empty());
+    fs::remove_dir_all(dbdir).unwrap();
+}
+
+#[test]
+fn test_prevalence() {
+    let dbdir = scratch_dbdir("prevalence");
+    let db = HIBPDB::new(dbdir.clone()).unwrap();
+
+    let hash = ntlm("password");
+    let range = hash_prefix(&hash);
+    let content = format!("{}:123\r\n{}:5\r\n", &HASH_to_hex(&hash)[5..], "0".repeat(27));
+    db.save(HashRange{range, etag: 1, timestamp: 1_700_000_000, codec: Codec::Gz, compressed: compress_gz(content.as_bytes()).unwrap()}).unwrap();
+
+    assert_eq!(db.prevalence(&hash).unwrap(), Some(123));
+    assert_eq!(db.prevalence(&hash_with_prefix(range, 1)).unwrap(), None);
+    assert_eq!(db.prevalence(&hash_with_prefix(range+1, 1)).unwrap_err().kind(), std::io::ErrorKind::NotFound);
+
+    // range/ is listed once per db; a range saved afterwards shows up in a db opened later.
+    db.save(HashRange{range: range+1, etag: 1, timestamp: 1_700_000_000, codec: Codec::Gz, compressed: compress_gz(content.as_bytes()).unwrap()}).unwrap();
+    assert_eq!(db.prevalence(&hash_with_prefix(range+1, 1)).unwrap_err().kind(), std::io::ErrorKind::NotFound);
+    assert_eq!(HIBPDB::new(dbdir.clone()).unwrap().prevalence(&hash_with_prefix(range+1, 1)).unwrap(), None);
+    fs::remove_dir_all(dbdir).unwrap();
+}
+
+#[test]
+fn test_progress() {
+    let mut stats = ProgressStats { done: 7, ..Default::default() };
+    stats.record(&ProgressEvent::Started { task: "update", total: 4 });
+    assert_eq!((stats.task, stats.total, stats.done), ("update", 4, 0));
+    assert_eq!(stats.eta(), None);
+
+    stats.record(&ProgressEvent::Advanced { range: 0, bytes: 100 });
+    stats.record(&ProgressEvent::Failed { range: 1, error: String::from("retrying") });
+    stats.record(&ProgressEvent::Advanced { range: 1, bytes: 50 });
+    assert_eq!((stats.done, stats.bytes, stats.errors), (2, 150, 1));
+    assert_eq!(stats.fraction(), 0.5);
+    std::thread::sleep(std::time::Duration::from_millis(10));
+    assert!(stats.eta().is_some());
+
+    stats.record(&ProgressEvent::Finished);
+    let elapsed = stats.elapsed();
+    std::thread::sleep(std::time::Duration::from_millis(10));
+    assert_eq!(stats.elapsed(), elapsed);
+
+    let mut ranges: Vec<u32> = Vec::new();
+    let mut callback = RangeCallback(|range| ranges.push(range));
+    callback.event(ProgressEvent::Started { task: "construct", total: 2 });
+    callback.event(ProgressEvent::Advanced { range: 5, bytes: 16 });
+    callback.event(ProgressEvent::Failed { range: 6, error: String::new() });
+    callback.event(ProgressEvent::Finished);
+    assert_eq!(ranges, [5]);
+}
+
+#[test]
+fn test_update_throttle() {
+    let ms = std::time::Duration::from_millis;
+    let mut bucket = TokenBucket::new(10.0, 2.0);
+    let start = std::time::Instant::now();
+    assert_eq!(bucket.reserve_at(1.0, start), ms(0));
+    assert_eq!(bucket.reserve_at(1.0, start), ms(0));
+    assert_eq!(bucket.reserve_at(1.0, start), ms(100));
+    assert_eq!(bucket.reserve_at(1.0, start + ms(100)), ms(100));
+    assert_eq!(bucket.reserve_at(0.0, start + ms(1000)), ms(0));
+
+    let time = |v: &str| chrono::NaiveTime::parse_from_str(v, "%H:%M").unwrap();
+    let night: ScheduleWindow = "19:00-07:00".parse().unwrap();
+    assert!(night.contains(time("23:30")) && night.contains(time("06:59")));
+    assert!(!night.contains(time("07:00")));
+    assert_eq!(night.wait(time("18:00")), std::time::Duration::from_secs(3600));
+    assert_eq!(night.wait(time("02:00")), std::time::Duration::ZERO);
+    let day: ScheduleWindow = "09:00-17:00".parse().unwrap();
+    assert_eq!(day.wait(time("17:00")), std::time::Duration::from_secs(16*3600));
+    assert!("9-17".parse::<ScheduleWindow>().is_err());
+
+    assert_eq!(parse_bytes("512K"), Ok(512 << 10));
+    assert_eq!(parse_bytes("2m"), Ok(2 << 20));
+    assert_eq!(parse_bytes("1000"), Ok(1000));
+    assert!(parse_bytes("fast").is_err());
+    assert!(parse_bytes("99999999999G").is_err());
+    assert_eq!(UpdateOptions::default().concurrency, 500);
+}
+
+#[test]
+fn test_partial_corpus() {
+    let prefixes: PrefixRange = "ABCD0-ABCDF".parse().unwrap();
+    assert_eq!((prefixes.start, prefixes.end, prefixes.count()), (0xABCD0, 0xABCDF, 16));
+    assert_eq!("00000-FFFFF".parse::<PrefixRange>().unwrap(), PrefixRange::FULL);
+    assert_eq!("12345".parse::<PrefixRange>().unwrap().to_string(), "12345-12345");
+    assert!("3FFFF-00000".parse::<PrefixRange>().is_err());
+    assert!("0-3FFFF".parse::<PrefixRange>().is_err());
+
+    let dbdir = scratch_dbdir("partial");
+    let mut db = HIBPDB::new(dbdir.clone()).unwrap();
+    save_ranges(&db, prefixes, None);
+    assert_eq!(db.range_map().unwrap_err().kind(), std::io::ErrorKind::NotFound);
+
+    db.prefixes = prefixes;
+    assert_eq!(db.range_map().unwrap().iter().filter(|v| !v.is_empty()).count(), 16);
+    db.construct_index(|_| {}).unwrap();
+    db.prefixes = "ABCD0-ABCE0".parse().unwrap();
+    assert_eq!(db.range_map().unwrap_err().to_string(), "missing ABCE0");
+
+    let db = HIBPDB::new(dbdir.clone()).unwrap();
+    assert_eq!(db.coverage, prefixes);
+    assert_eq!((db.len(), db.strategy()), (32, SearchStrategy::Interpolation));
+    let mut hit: HASH = [0u8; 16];
+    hex::decode_to_slice(format!("ABCD3{:027X}", 0xABCD3), &mut hit).unwrap();
+    assert_eq!(db.lookup(&hit), Lookup::Found);
+    // Misses at either end of the covered interval stay inside index.bin.
+    let mut last: HASH = [0xFFu8; 16];
+    hex::decode_to_slice(format!("ABCDF{}", "F".repeat(27)), &mut last).unwrap();
+    assert_eq!(db.lookup(&last), Lookup::NotFound);
+    assert_eq!(db.find(&last).unwrap(), Err(32));
+    assert_eq!(db.find(&hash_with_prefix(0xABCD0, 0)).unwrap(), Err(0));
+    assert!(db.index().iter().enumerate().all(|(i, v)| db.find(v).unwrap() == Ok(i)));
+    assert_eq!(db.lookup(&hash_with_prefix(0xABCD3, 7)), Lookup::NotFound);
+    assert_eq!(db.lookup(&hash_with_prefix(0x00001, 1)), Lookup::NotCovered);
+    assert!(!db.contains(&hash_with_prefix(0xABCD3, 7)).unwrap());
+    assert_eq!(db.contains(&hash_with_prefix(0x00001, 1)).unwrap_err().kind(), std::io::ErrorKind::InvalidInput);
+    assert_eq!(db.find(&hash_with_prefix(0x00001, 1)).unwrap_err().kind(), std::io::ErrorKind::InvalidInput);
+    assert!(db.verify(4, |_| {}).unwrap().is_ok());
+    drop(db);
+
+    // Ranges without records give an empty index.bin, which still answers inside its coverage.
+    let empty = scratch_dbdir("partial_empty");
+    fs::write(empty.clone()+"/index.bin", []).unwrap();
+    fs::copy(dbdir.clone()+"/index.coverage", empty.clone()+"/index.coverage").unwrap();
+    let db = HIBPDB::new(empty.clone()).unwrap();
+    assert_eq!(db.lookup(&hash_with_prefix(0xABCD3, 7)), Lookup::NotFound);
+    assert_eq!(db.find(&hash_with_prefix(0xABCD3, 7)).unwrap(), Err(0));
+    fs::remove_dir_all(empty).unwrap();
+    fs::remove_dir_all(dbdir).unwrap();
+}
+
+#[test]
+fn test_sharded_index() {
+    let parts = split_prefixes(PrefixRange::FULL, 3);
+    assert_eq!(parts.iter().map(|v| v.count()).collect::<Vec<_>>(), [349526, 349525, 349525]);
+    assert_eq!((parts[0].start, parts[2].end), (0, 0xFFFFF));
+    assert!(parts.windows(2).all(|v| v[0].end + 1 == v[1].start));
+
+    let dbdir = scratch_dbdir("shards");
+    let mut db = HIBPDB::new(dbdir.clone()).unwrap();
+    db.prefixes = "ABCD0-ABCDF".parse().unwrap();
+    // The last shard ends up empty.
+    save_ranges(&db, db.prefixes, Some(0xABCDC));
+    db.construct_index(|_| {}).unwrap();
+    let full = HIBPDB::new(dbdir.clone()).unwrap();
+    let keys: Vec<HASH> = full.index().iter().rev().copied().chain([hash_with_prefix(0xABCD5, 9), hash_with_prefix(0xABCDE, 1), hash_with_prefix(0x00001, 1)]).collect();
+    let expected: Vec<Result<usize, usize>> = keys[..26].iter().map(|v| full.find(v).unwrap()).collect();
+    drop(full);
+
+    db.shard_count = 4;
+    db.construct_index(|_| {}).unwrap();
+    assert!(fs::metadata(dbdir.clone()+"/index.bin").is_err());
+    let manifest = ShardManifest::load((dbdir.clone()+"/"+SHARD_MANIFEST).as_str()).unwrap();
+    assert_eq!(manifest.shards.iter().map(|v| v.records).collect::<Vec<_>>(), [8, 8, 8, 0]);
+    assert_eq!(manifest.route(0xABCD5), Some(1));
+    assert_eq!(manifest.route(0xABCE0), None);
+
+    // An approximate filter next to the shards must not take over membership.
+    FilterWriter::create((dbdir.clone()+"/"+FILTER_INDEX).as_str(), 4, 0).unwrap().finish().unwrap();
+    let db = HIBPDB::new(dbdir.clone()).unwrap();
+    assert_eq!(db.len(), 24);
+    assert_eq!(keys[..26].iter().map(|v| db.find(v).unwrap()).collect::<Vec<_>>(), expected);
+    assert_eq!(db.find_batch(&keys[..26]).unwrap(), expected);
+    assert_eq!(db.find_batch(&keys).unwrap_err().kind(), std::io::ErrorKind::InvalidInput);
+    let lookups = db.lookup_batch(&keys);
+    assert_eq!(lookups[..24], [Lookup::Found; 24]);
+    assert_eq!(lookups[24..], [Lookup::NotFound, Lookup::NotFound, Lookup::NotCovered]);
+    drop(db);
+
+    // Rebuilding with fewer shards, or none, removes the shard files left over.
+    let mut db = HIBPDB::new(dbdir.clone()).unwrap();
+    db.prefixes = "ABCD0-ABCDF".parse().unwrap();
+    db.shard_count = 2;
+    db.construct_index(|_| {}).unwrap();
+    // The db that built the index answers from the new files straight away.
+    assert_eq!((db.len(), db.coverage), (24, db.prefixes));
+    assert_eq!(db.find_batch(&keys[..26]).unwrap(), expected);
+    assert!(fs::metadata(dbdir.clone()+"/index.001.bin").is_ok());
+    assert!(fs::metadata(dbdir.clone()+"/index.shards.tmp").is_err());
+    assert!(fs::metadata(dbdir.clone()+"/index.002.bin").is_err());
+    db.shard_count = 1;
+    db.construct_index(|_| {}).unwrap();
+    assert_eq!(db.find_batch(&keys[..26]).unwrap(), expected);
+    assert!(fs::metadata(dbdir.clone()+"/index.000.bin").is_err());
+    assert_eq!(HIBPDB::new(dbdir.clone()).unwrap().len(), 24);
+    fs::remove_dir_all(dbdir).unwrap();
+}
+
 mod tests {
     use std::env;
     use hibp_core::db::HIBPDB;
@@ -40,6 +833,8 @@ mod tests {
     }
 
     #[test]
+    #[ignore = "needs DB_DIRECTORY pointing at a full database"]
+    #[allow(clippy::assertions_on_constants)]
     fn test_interpolation_search() {
         let db = HIBPDB::new(db_directory()).unwrap();
 
@@ -72,3 +867,108 @@ mod tests {
 
 
 
+
+// Not run: the futures only have to be Send so a service can tokio::spawn them.
+#[allow(dead_code)]
+fn test_async_send(db: &mut HIBPDB, progress: &mut (dyn Progress + Send)) {
+    fn assert_send<T: Send>(_: T) {}
+    assert_send(db.update_async(&UpdateOptions::default(), progress));
+    assert_send(db.construct_index_async(progress));
+    assert_send(db.recompress_async(Codec::Zstd, |_| {}));
+    assert_send(db.verify_async(4, |_| {}));
+    assert_send(db.validate_ranges_async(|_| {}));
+    assert_send(db.repair_async(&[], |_| {}));
+    assert_send(db.lookup_batch_async(&[]));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_async_api() {
+    let dbdir = scratch_dbdir("async");
+    let mut db = HIBPDB::new(dbdir.clone()).unwrap();
+    db.prefixes = "ABCD0-ABCDF".parse().unwrap();
+    save_ranges(&db, db.prefixes, None);
+    db.recompress_async(Codec::Zstd, |_| {}).await.unwrap();
+    assert_eq!(db.validate_ranges_async(|_| {}).await.unwrap().bad_ranges.len(), RANGE_COUNT - 16);
+    let mut done: Vec<u32> = Vec::new();
+    db.construct_index_async(&mut RangeCallback(|range| done.push(range))).await.unwrap();
+    assert_eq!(done.len(), 16);
+
+    let db = HIBPDB::new(dbdir.clone()).unwrap();
+    assert!(db.verify_async(4, |_| {}).await.unwrap().is_ok());
+    let keys: Vec<HASH> = vec![hash_with_prefix(0xABCD3, 1), hash_with_prefix(0xABCD3, 7), hash_with_prefix(0x00001, 1)];
+    let expected = vec![Lookup::Found, Lookup::NotFound, Lookup::NotCovered];
+    assert_eq!(db.lookup_batch_async(&keys).await, expected);
+    let streamed: Vec<(HASH, Lookup)> = db.lookup_stream(futures::stream::iter(keys.clone())).collect().await;
+    assert_eq!(streamed, keys.into_iter().zip(expected).collect::<Vec<_>>());
+    fs::remove_dir_all(dbdir).unwrap();
+}
+
+#[test]
+fn test_reader() {
+    fn shareable<T: Send + Sync + Clone>(_: &T) {}
+
+    let dbdir = scratch_dbdir("reader");
+    assert_eq!(Reader::open(dbdir.clone(), IndexOptions::default()).err().unwrap().kind(), std::io::ErrorKind::NotFound);
+    let mut db = HIBPDB::new(dbdir.clone()).unwrap();
+    db.prefixes = "ABCD0-ABCDF".parse().unwrap();
+    save_ranges(&db, db.prefixes, None);
+    db.construct_index(|_| {}).unwrap();
+
+    let db = HIBPDB::new(dbdir.clone()).unwrap();
+    let keys: Vec<HASH> = vec![hash_with_prefix(0xABCD3, 1), hash_with_prefix(0xABCD3, 7), hash_with_prefix(0x00001, 1)];
+    let expected = db.lookup_batch(&keys);
+    let positions = db.find_batch(&keys[..2]).unwrap();
+
+    let reader = Reader::try_from(db).unwrap();
+    shareable(&reader);
+    assert_eq!((reader.len(), reader.coverage()), (32, "ABCD0-ABCDF".parse().unwrap()));
+    assert_eq!(reader.find_batch(&keys[..2]).unwrap(), positions);
+    let threads: Vec<_> = (0..4).map(|_| {
+        let (reader, keys) = (reader.clone(), keys.clone());
+        std::thread::spawn(move || reader.lookup_batch(&keys))
+    }).collect();
+    for t in threads {
+        assert_eq!(t.join().unwrap(), expected);
+    }
+    assert_eq!(expected, vec![Lookup::Found, Lookup::NotFound, Lookup::NotCovered]);
+
+    // Lookups never open the range archive, so a damaged one only fails the calls that need it.
+    fs::write(dbdir.clone()+"/range.pack", b"").unwrap();
+    fs::write(dbdir.clone()+"/range.idx", b"garbage").unwrap();
+    let reader = Reader::open(dbdir.clone(), IndexOptions::default()).unwrap();
+    assert_eq!(reader.find_batch(&keys[..2]).unwrap(), positions);
+    let db = HIBPDB::new(dbdir.clone()).unwrap();
+    assert_eq!(db.prevalence(&keys[0]).err().unwrap().kind(), std::io::ErrorKind::InvalidData);
+    fs::remove_dir_all(dbdir).unwrap();
+}
+
+#[test]
+fn test_concurrent_lookups() {
+    fn shareable<T: Send + Sync>(_: &T) {}
+
+    let mut rng: RandomItemGenerator<HASH> = RandomItemGenerator::new(1000);
+    let mut sorted: Vec<HASH> = (0..20_000).map(|_| *rng.next_item()).collect();
+    sorted.sort();
+    sorted.dedup();
+    let keys: Vec<HASH> = sorted.iter().step_by(3).copied().chain((0..5_000).map(|_| *rng.next_item())).collect();
+
+    let dbdir = scratch_dbdir("concurrent");
+    fs::write(dbdir.clone()+"/index.bin", sorted.concat()).unwrap();
+    for in_memory in [false, true] {
+        let mut db = HIBPDB::open(dbdir.clone(), IndexOptions { in_memory, ..Default::default() }).unwrap();
+        db.construct_eytzinger().unwrap();
+        shareable(&db);
+
+        for strategy in [SearchStrategy::Binary, SearchStrategy::Eytzinger] {
+            db.set_strategy(strategy).unwrap();
+            let expected: Vec<bool> = keys.iter().map(|v| sorted.binary_search(v).is_ok()).collect();
+            let db = &db;
+            std::thread::scope(|s| {
+                let threads: Vec<_> = keys.chunks(1000).map(|chunk| s.spawn(move || chunk.iter().map(|v| db.contains(v).unwrap()).collect::<Vec<bool>>())).collect();
+                let found: Vec<bool> = threads.into_iter().flat_map(|t| t.join().unwrap()).collect();
+                assert_eq!(found, expected);
+            });
+        }
+    }
+    fs::remove_dir_all(dbdir).unwrap();
+}